@@ -0,0 +1,1605 @@
+//! Template engine for nameit
+//!
+//! Parses the name formats, renders them for files and plans the
+//! renames with [`Planner`] without touching the files. The command
+//! line tool adds the prompts, history and the operations on top of
+//! this.
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Datelike, Local, Locale};
+use colored::Colorize;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+use xxhash_rust::xxh64::Xxh64;
+
+/// Part of the name format
+#[derive(Clone)]
+pub enum NamePart<'a> {
+    String(&'a str),
+    Variable(&'a str),
+    /// Value given in the format with `{=value}`
+    Value(&'a str),
+    Parameter(&'a str),
+    /// Part of the old filename matching the regex, `{/regex/}` or
+    /// `{/regex/N}` for the Nth capture group
+    Capture(&'a str, Regex, Option<usize>),
+    Delimiter(&'a str),
+}
+
+/// Parsed name format
+#[derive(Clone)]
+pub struct NameTemplate<'a> {
+    pub parts: Vec<NamePart<'a>>,
+    /// Separator between the parts, also used to split the old name
+    pub delimiter: char,
+}
+
+/// Problem in the format, with the position (in bytes) where it is
+#[derive(Clone, Debug, PartialEq)]
+pub enum TemplateParseError {
+    /// `{` inside a literal
+    UnexpectedOpen(usize),
+    /// `}` outside of a literal
+    UnexpectedClose(usize),
+    /// `{` without its `}`
+    Unclosed(usize),
+    /// Nothing between the braces of a literal
+    EmptyVariable(usize),
+    /// Variable modifier (after `:`) that doesn't exist
+    UnknownModifier(usize, String),
+    /// Regex in `{/regex/}` that can't be compiled
+    InvalidRegex(usize, String),
+    /// Special parameter (starting with `%*?#@`) that doesn't exist
+    UnknownParameter(usize, String),
+    /// More than one character after `:group`
+    GroupSeparator(usize, String),
+}
+
+impl std::fmt::Display for TemplateParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnexpectedOpen(i) => write!(f, "Invalid Format: unexpected '{{' at {}", i),
+            Self::UnexpectedClose(i) => write!(f, "Invalid Format: unexpected '}}' at {}", i),
+            Self::Unclosed(i) => write!(f, "Invalid Format: unclosed '{{' at {}", i),
+            Self::EmptyVariable(i) => write!(f, "Invalid Format: empty '{{}}' at {}", i),
+            Self::UnknownModifier(i, e) => write!(f, "Invalid Format: {} (at {})", e, i),
+            Self::InvalidRegex(i, e) => write!(f, "Invalid Format: bad regex at {}: {}", i, e),
+            Self::UnknownParameter(i, p) => {
+                write!(f, "Invalid Format: unknown parameter {:?} at {}", p, i)
+            }
+            Self::GroupSeparator(i, s) => write!(
+                f,
+                "Invalid Format: group separator {:?} at {} should be one character",
+                s, i
+            ),
+        }
+    }
+}
+
+impl Error for TemplateParseError {}
+
+impl<'a> TryFrom<&'a str> for NameTemplate<'a> {
+    type Error = TemplateParseError;
+
+    fn try_from(st: &'a str) -> Result<Self, Self::Error> {
+        Self::parse(st, '_')
+    }
+}
+
+impl<'a> NameTemplate<'a> {
+    /// Checks if the special parameter is used in the template
+    pub fn has_parameter(&self, param: &str) -> bool {
+        self.parts
+            .iter()
+            .any(|p| matches!(p, NamePart::Parameter(q) if *q == param))
+    }
+
+    /// Checks if any `@meta:N` parameter is used in the template
+    pub fn uses_meta(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|p| matches!(p, NamePart::Parameter(q) if q.starts_with("@meta:")))
+    }
+
+    /// Parses the format with the given delimiter between the parts
+    pub fn parse(st: &'a str, delimiter: char) -> Result<Self, TemplateParseError> {
+        let mut var_parts = Vec::<NamePart>::new();
+        let mut last: usize = 0;
+        let mut flag = false;
+        let mut chars = st.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            match (c, flag) {
+                // `{{` and `}}` outside literals are the braces themselves
+                ('{', false) | ('}', false) if chars.peek().map(|(_, n)| *n) == Some(c) => {
+                    chars.next();
+                    if i != last {
+                        var_parts.push(NamePart::Variable(&st[last..i]));
+                    }
+                    var_parts.push(NamePart::String(&st[i..i + 1]));
+                    last = i + 2;
+                }
+                ('{', false) if st[i + 1..].starts_with('/') => {
+                    // the regex can have braces, so it ends at the first
+                    // `/}` or `/N}`
+                    if i != last {
+                        var_parts.push(NamePart::Variable(&st[last..i]));
+                    }
+                    let (end, close, group) = st[i + 2..]
+                        .match_indices('/')
+                        .find_map(|(j, _)| {
+                            let start = i + 3 + j;
+                            let digits = st[start..].find(|c: char| !c.is_ascii_digit())?;
+                            st[start + digits..].starts_with('}').then(|| {
+                                let group = st[start..start + digits].parse::<usize>().ok();
+                                (i + 2 + j, start + digits, group)
+                            })
+                        })
+                        .ok_or(TemplateParseError::Unclosed(i))?;
+                    let pattern = &st[i + 2..end];
+                    let re = Regex::new(pattern)
+                        .map_err(|e| TemplateParseError::InvalidRegex(i, e.to_string()))?;
+                    if let Some(n) = group.filter(|n| *n >= re.captures_len()) {
+                        let e = format!("no capture group {} in {:?}", n, pattern);
+                        return Err(TemplateParseError::InvalidRegex(i, e));
+                    }
+                    var_parts.push(NamePart::Capture(&st[i + 1..close], re, group));
+                    last = close + 1;
+                    while chars.next_if(|(j, _)| *j < last).is_some() {}
+                }
+                ('{', false) => {
+                    if i != last {
+                        var_parts.push(NamePart::Variable(&st[last..i]));
+                    }
+                    last = i + 1;
+                    flag = true;
+                }
+                ('{', true) => return Err(TemplateParseError::UnexpectedOpen(i)),
+                ('}', true) => {
+                    if let Some(v) = st[last..i].strip_prefix('=') {
+                        var_parts.push(NamePart::Value(v));
+                    } else if i != last {
+                        var_parts.push(NamePart::String(&st[last..i]));
+                    } else {
+                        return Err(TemplateParseError::EmptyVariable(i - 1));
+                    }
+                    last = i + 1;
+                    flag = false;
+                }
+                ('}', false) => return Err(TemplateParseError::UnexpectedClose(i)),
+                (c, false) if c == delimiter => {
+                    if i != last {
+                        var_parts.push(NamePart::Variable(&st[last..i]));
+                    }
+                    last = i + c.len_utf8();
+                    var_parts.push(NamePart::Delimiter(&st[i..last]));
+                }
+                _ => (),
+            }
+        }
+        if flag {
+            return Err(TemplateParseError::Unclosed(last - 1));
+        }
+        if last != st.len() {
+            var_parts.push(NamePart::Variable(&st[last..]));
+        }
+
+        let parts = var_parts
+            .into_iter()
+            .map(|var| {
+                if let NamePart::Variable(v) = var {
+                    if v.starts_with('%') && !is_date_format(v) {
+                        // not a valid date format so use it as it is
+                        Ok(NamePart::String(v))
+                    } else {
+                        match v.chars().next() {
+                            Some(c) if "%*?#@".contains(c) => {
+                                let pos = v.as_ptr() as usize - st.as_ptr() as usize;
+                                if let Some((num, sep)) = split_group(v) {
+                                    if sep.chars().nth(1).is_some() {
+                                        return Err(TemplateParseError::GroupSeparator(
+                                            pos + num.len() + ":group".len(),
+                                            sep.to_string(),
+                                        ));
+                                    }
+                                }
+                                if is_parameter(v) {
+                                    Ok(NamePart::Parameter(v))
+                                } else {
+                                    Err(TemplateParseError::UnknownParameter(pos, v.to_string()))
+                                }
+                            }
+                            Some(_) => match split_modifier(v) {
+                                (name, Some(m)) => match m.parse::<Modifier>() {
+                                    Ok(_) => Ok(var),
+                                    Err(e) => Err(TemplateParseError::UnknownModifier(
+                                        v.as_ptr() as usize - st.as_ptr() as usize + name.len() + 1,
+                                        e,
+                                    )),
+                                },
+                                (_, None) => Ok(var),
+                            },
+                            None => Err(TemplateParseError::EmptyVariable(
+                                v.as_ptr() as usize - st.as_ptr() as usize,
+                            )),
+                        }
+                    }
+                } else {
+                    Ok(var)
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { parts, delimiter })
+    }
+}
+
+/// Checks if the string only has valid date time specifiers
+pub fn is_date_format(s: &str) -> bool {
+    StrftimeItems::new(s).all(|i| !matches!(i, Item::Error))
+}
+
+impl std::fmt::Display for NameTemplate<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for p in &self.parts {
+            match p {
+                NamePart::String(s) => write!(f, "{}", s)?,
+                NamePart::Delimiter(d) => write!(f, "{}", d)?,
+                NamePart::Variable(v) | NamePart::Value(v) => write!(f, "{}", v.on_blue())?,
+                NamePart::Parameter(v) => write!(f, "{}", v.on_yellow())?,
+                NamePart::Capture(spec, ..) => {
+                    write!(f, "{}", format!("{{{}}}", spec).on_yellow())?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Saved formats, values for the variables and their settings
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct History {
+    #[serde(default)]
+    pub formats: Vec<String>,
+    #[serde(default)]
+    pub variables: BTreeSet<String>,
+    #[serde(default)]
+    pub values: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub validators: BTreeMap<String, String>,
+    #[serde(default)]
+    pub suggestions: BTreeMap<String, String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+    #[serde(default)]
+    pub default_format: Option<String>,
+    #[serde(default)]
+    pub max_values: Option<usize>,
+    #[serde(default)]
+    pub destinations: Vec<String>,
+    #[serde(default)]
+    pub daily: Option<DailyCounter>,
+    /// Delimiter of the formats that don't use `_`
+    #[serde(default)]
+    pub delimiters: BTreeMap<String, char>,
+}
+
+/// Last number given by `@daily` and the day it was given on
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DailyCounter {
+    pub date: String,
+    pub last: usize,
+}
+
+impl History {
+    /// Variable whose values are used for the given variable
+    pub fn alias_target<'a>(&'a self, var: &'a str) -> &'a str {
+        let mut target = var;
+        // limit the depth in case the aliases make a loop
+        for _ in 0..self.aliases.len() {
+            match self.aliases.get(target) {
+                Some(t) => target = t,
+                None => break,
+            }
+        }
+        target
+    }
+
+    /// Delimiter to parse a saved format with
+    ///
+    /// Formats are saved with the delimiter they were entered with when
+    /// it isn't `_`, asking for a different one (other than the default
+    /// `_`) is an error.
+    pub fn format_delimiter(&self, fmt: &str, delimiter: char) -> Result<char, String> {
+        let saved = self.delimiters.get(fmt).copied().unwrap_or('_');
+        if delimiter != '_' && delimiter != saved {
+            return Err(format!(
+                "Format {:?} was saved with the delimiter {:?}, not {:?}",
+                fmt, saved, delimiter
+            ));
+        }
+        Ok(saved)
+    }
+
+    /// Saves the delimiter for a new format if it isn't `_`
+    pub fn set_delimiter(&mut self, fmt: &str, delimiter: char) {
+        if delimiter != '_' {
+            self.delimiters.insert(fmt.to_string(), delimiter);
+        }
+    }
+
+    /// Format to use with `--last`, the default one or the latest
+    pub fn last_format(&self) -> Option<&String> {
+        self.default_format.as_ref().or(self.formats.first())
+    }
+}
+
+pub fn save_history(fname: &PathBuf, history: &History) -> Result<(), Box<dyn Error>> {
+    let par = fname.parent().unwrap();
+    if !par.exists() {
+        std::fs::create_dir_all(par)?;
+    }
+    let mut history = history.clone();
+    if let Some(max) = history.max_values {
+        // the latest values are at the start
+        for vals in history.values.values_mut() {
+            vals.truncate(max);
+        }
+    }
+    let file = File::create(fname)?;
+    let writer = BufWriter::new(file);
+    serde_json::to_writer(writer, &history)?;
+    Ok(())
+}
+
+pub fn read_history(path: &PathBuf) -> Result<History, Box<dyn Error>> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                return Ok(History::default());
+            } else {
+                return Err(Box::new(e));
+            }
+        }
+    };
+    let reader = BufReader::new(file);
+    let hist = serde_json::from_reader(reader)?;
+    Ok(hist)
+}
+
+/// What is done with the file
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    #[serde(rename = "Copy")]
+    Copy,
+    #[serde(rename = "Rename")]
+    Rename,
+    #[serde(rename = "Move")]
+    Move,
+}
+
+impl Action {
+    /// Name of the action as saved in the plans and journals
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Copy => "Copy",
+            Self::Rename => "Rename",
+            Self::Move => "Move",
+        }
+    }
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Operation saved in a plan file to apply later
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlannedOperation {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub action: Action,
+    /// Size of the source, if it exists
+    #[serde(default)]
+    pub bytes: Option<u64>,
+    /// Modification time in seconds since the unix epoch
+    #[serde(default)]
+    pub modified: Option<u64>,
+    /// Problems found while planning the new name
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+impl PlannedOperation {
+    /// Operation with the size and time of the source if it exists
+    ///
+    /// The source doesn't need to exist, so the names can be planned
+    /// for files that are not there yet.
+    pub fn new(source: &Path, destination: &Path, action: Action) -> Self {
+        let stamp = file_stamp(source).ok();
+        Self {
+            source: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+            action,
+            bytes: stamp.map(|s| s.0),
+            modified: stamp.map(|s| s.1),
+            warnings: Vec::new(),
+        }
+    }
+}
+
+/// Size and modification time of the file to check it hasn't changed
+pub fn file_stamp(path: &Path) -> Result<(u64, u64), Box<dyn Error>> {
+    let meta = path.symlink_metadata()?;
+    let modified = meta
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    Ok((meta.len(), modified))
+}
+
+/// Hash of the contents of a file
+///
+/// It's the XXH64 hash (seed 0), so it stays the same across versions
+/// and platforms.
+pub fn content_hash(path: &Path) -> Result<u64, Box<dyn Error>> {
+    let mut hasher = Xxh64::new(0);
+    let mut reader = BufReader::new(File::open(path)?);
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        hasher.update(buf);
+        let len = buf.len();
+        reader.consume(len);
+    }
+    Ok(hasher.digest())
+}
+
+/// Short id from the contents of the file if the template uses `@cid`
+///
+/// It is the first 10 characters of the content hash in lowercase
+/// base32, so it's safe for filenames even on case insensitive systems.
+pub fn content_id(templ: &NameTemplate, path: &Path) -> Result<Option<String>, Box<dyn Error>> {
+    if !templ.has_parameter("@cid") {
+        return Ok(None);
+    }
+    const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let hash = content_hash(path)?;
+    let id = (0..10)
+        .map(|i| ALPHABET[((hash >> (59 - 5 * i)) & 31) as usize] as char)
+        .collect();
+    Ok(Some(id))
+}
+
+/// Format from the `.nameit` sidecar file of the given file
+///
+/// The sidecar for `photo.jpg` is `photo.jpg.nameit`, its first line
+/// is used as the format.
+pub fn read_sidecar(path: &Path) -> Result<Option<String>, Box<dyn Error>> {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".nameit");
+    match std::fs::read_to_string(sidecar) {
+        Ok(s) => Ok(s.lines().next().map(|l| l.trim().to_string())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+/// Lines of the metadata sidecar file of the given file
+///
+/// The metadata file for `photo.jpg` is `photo.meta` (with the
+/// default extension).
+pub fn read_meta(path: &Path, ext: &str) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+    match std::fs::read_to_string(path.with_extension(ext)) {
+        Ok(s) => Ok(Some(s.lines().map(|l| l.to_string()).collect())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+/// When to restart the counter
+#[derive(Clone, Copy, Default)]
+pub enum CounterReset {
+    /// Single counter for the whole batch
+    #[default]
+    Never,
+    /// Separate counter for each file extension
+    Extension,
+}
+
+/// Counter for the `#` parameter
+#[derive(Clone)]
+pub struct Counter {
+    /// First number
+    pub start: usize,
+    /// Increment between the files
+    pub step: usize,
+    /// Minimum width of the number, number of `#` is used if larger
+    pub width: usize,
+    /// Base of the number (2 to 36), set with [`Counter::with_base`]
+    base: u32,
+    /// When to restart the counter
+    pub reset: CounterReset,
+    /// Next number for each of the counter groups
+    pub next: HashMap<String, usize>,
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self {
+            start: 1,
+            step: 1,
+            width: 0,
+            base: 10,
+            reset: CounterReset::Never,
+            next: HashMap::new(),
+        }
+    }
+}
+
+impl Counter {
+    /// Counter with the numbers in the base, which is from 2 to 36
+    pub fn with_base(mut self, base: u32) -> Result<Self, String> {
+        if !(2..=36).contains(&base) {
+            return Err(format!("Invalid base {}, use 2 to 36", base));
+        }
+        self.base = base;
+        Ok(self)
+    }
+
+    /// Base of the numbers
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    /// Gives the number for the file and advances the counter
+    pub fn next(&mut self, path: &Path) -> usize {
+        let key = match self.reset {
+            CounterReset::Never => String::new(),
+            CounterReset::Extension => path
+                .extension()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+        };
+        let next = self.next.entry(key).or_insert(self.start);
+        let num = *next;
+        *next += self.step;
+        num
+    }
+
+    /// Formats the number with at least `width` digits
+    pub fn format(&self, num: usize, width: usize) -> String {
+        let mut digits = Vec::new();
+        let mut n = num;
+        loop {
+            digits.push(std::char::from_digit((n % self.base as usize) as u32, self.base).unwrap());
+            n /= self.base as usize;
+            if n == 0 {
+                break;
+            }
+        }
+        let digits: String = digits.into_iter().rev().collect();
+        format!("{0:0>1$}", digits, width.max(self.width))
+    }
+}
+
+/// Information about the file being renamed used by the special parameters
+pub struct FileInfo<'a> {
+    /// Filename without the extension
+    pub stem: &'a str,
+    /// Number for the `#` parameter
+    pub num: usize,
+    /// Counter to format the number with
+    pub counter: &'a Counter,
+    /// Number of files in the batch
+    pub total: usize,
+    /// Permission bits of the file (unix only)
+    pub mode: Option<u32>,
+    /// Keep the `_` inside brackets when splitting the name into parts
+    pub split_brackets: bool,
+    /// Separator for the parts of the old filename
+    pub delimiter: char,
+    /// Lines of the metadata sidecar file if it exists
+    pub meta: Option<Vec<String>>,
+    /// Short id from the contents, only if the format uses `@cid`
+    pub cid: Option<String>,
+    /// Date used for the date time parameters
+    pub date: DateTime<Local>,
+    /// Locale for the month and day names in dates
+    pub locale: Locale,
+}
+
+/// Cleans up the rendered name to use as a filename
+///
+/// Spaces are replaced by `-`, with `ascii` the non ASCII characters
+/// are transliterated and with `lower` it is made lowercase. Only
+/// used on the name without the extension.
+pub fn sanitize(name: &str, ascii: bool, lower: bool) -> String {
+    let name = if ascii {
+        deunicode::deunicode(name).replace(' ', "-")
+    } else {
+        name.replace(' ', "-")
+    };
+    if lower {
+        name.to_lowercase()
+    } else {
+        name
+    }
+}
+
+/// New path for the file with the given name (without extension)
+///
+/// The extension of the file is kept, made lowercase with `lower_ext`.
+pub fn target_path(
+    filename: &Path,
+    fname: &str,
+    destination: Option<&Path>,
+    lower_ext: bool,
+) -> PathBuf {
+    let new_name = match filename.extension() {
+        None => filename.with_file_name(fname),
+        Some(e) => filename.with_file_name(format!(
+            // .with_extension() thing didn't work as it removes any
+            // part of the name after first '.' in filename
+            "{}.{}",
+            fname,
+            if lower_ext {
+                e.to_string_lossy().to_lowercase()
+            } else {
+                e.to_string_lossy().to_string()
+            },
+        )),
+    };
+    match destination {
+        // if destination is given discard the parent directory information
+        Some(d) => d.join(new_name.file_name().unwrap()),
+        None => new_name,
+    }
+}
+
+/// Checks if the string is one of the special parameters
+///
+/// Dates (starting with `%`) are checked with [`is_date_format`]
+/// before this, so any of them is accepted here.
+pub fn is_parameter(p: &str) -> bool {
+    if split_group(p).is_some() {
+        return true;
+    }
+    let is_number = |n: &str| n.parse::<usize>().is_ok();
+    p.starts_with('%')
+        || p.chars().all(|c| c == '#')
+        || p.chars().all(|c| c == '*')
+        || ["#o", "?", "@q", "#dir", "@daily", "@total", "@cid", "@mode"].contains(&p)
+        || p.strip_prefix("#when:").is_some_and(|v| !v.is_empty())
+        || p.strip_prefix("@meta:").is_some_and(is_number)
+        || p.strip_prefix("*~").is_some_and(is_number)
+}
+
+/// Number parameter and the separator of the `:group` suffix
+///
+/// Only the counter (`#`s) and `@total` can be grouped, so the other
+/// parameters like `#when:group` keep their `:group`.
+fn split_group(p: &str) -> Option<(&str, &str)> {
+    let (num, sep) = p.split_once(":group")?;
+    let is_num = !num.is_empty() && num.chars().all(|c| c == '#') || num == "@total";
+    is_num.then_some((num, sep))
+}
+
+/// Renders the special parameters (numbers, dates, old filename parts)
+///
+/// Only the parameters accepted by [`is_parameter`] are rendered, the
+/// rest are rejected when the format is parsed. Errors with the reason
+/// when the file doesn't have what the parameter needs, the part is
+/// usually left empty then.
+pub fn render_parameter(p: &str, file: &FileInfo) -> Result<String, String> {
+    if let Some((num, sep)) = split_group(p) {
+        // longer separators are rejected when the format is parsed
+        let sep = sep.chars().next().unwrap_or(',');
+        return Ok(group_digits(&render_parameter(num, file)?, sep));
+    }
+    let rendered = if p.chars().all(|c| c == '#') {
+        file.counter.format(file.num, p.len())
+    } else if p == "#o" {
+        ordinal(file.num)
+    } else if p == "?" {
+        file.stem.to_string()
+    } else if p.starts_with('%') {
+        file.date.format_localized(p, file.locale).to_string()
+    } else if p == "@q" {
+        format!("Q{}", file.date.month0() / 3 + 1)
+    } else if p.starts_with("#when:") || p == "#dir" || p == "@daily" {
+        // depends on the previous files, so it's filled in later
+        String::new()
+    } else if let Some(Ok(n)) = p.strip_prefix("@meta:").map(str::parse::<usize>) {
+        let line = file
+            .meta
+            .as_ref()
+            .and_then(|m| m.get(n.saturating_sub(1)))
+            .filter(|_| n > 0);
+        match line {
+            Some(l) => l.trim().to_string(),
+            None => {
+                return Err(format!(
+                    "no line {} in the metadata file for {:?}",
+                    n, file.stem
+                ))
+            }
+        }
+    } else if p == "@total" {
+        file.total.to_string()
+    } else if p == "@cid" {
+        file.cid.clone().unwrap_or_default()
+    } else if p == "@mode" {
+        match file.mode {
+            Some(m) => format!("{:o}", m),
+            None => return Err("file permissions are not available on this platform".to_string()),
+        }
+    } else if let Some(Ok(n)) = p.strip_prefix("*~").map(str::parse::<usize>) {
+        let chunks = stem_parts(file.stem, file.split_brackets, file.delimiter);
+        // clamp to the first chunk if there aren't enough of them
+        let n = n.clamp(1, chunks.len());
+        chunks[chunks.len() - n].to_string()
+    } else if p.chars().all(|c| c == '*') {
+        stem_parts(file.stem, file.split_brackets, file.delimiter)
+            .into_iter()
+            .take(p.len())
+            .collect::<Vec<&str>>()
+            .join(&file.delimiter.to_string())
+    } else {
+        String::new()
+    };
+    Ok(rendered)
+}
+
+/// Puts the separator between the groups of 3 digits from the end
+pub fn group_digits(num: &str, sep: char) -> String {
+    let len = num.chars().count();
+    let mut grouped = String::new();
+    for (i, c) in num.chars().enumerate() {
+        if i > 0 && (len - i).is_multiple_of(3) {
+            grouped.push(sep);
+        }
+        grouped.push(c);
+    }
+    grouped
+}
+
+/// Part of the old filename matching the regex
+///
+/// Without a group the first capture group is used if the regex has
+/// one, or else the whole match (group 0). It's empty if the group
+/// isn't in the match, and errors if the regex doesn't match.
+pub fn render_capture(re: &Regex, group: Option<usize>, file: &FileInfo) -> Result<String, String> {
+    match re.captures(file.stem) {
+        Some(c) => Ok(match group {
+            Some(n) => c.get(n),
+            None => c.get(1).or(c.get(0)),
+        }
+        .map_or(String::new(), |m| m.as_str().to_string())),
+        None => Err(format!(
+            "{:?} doesn't match the regex {:?}",
+            file.stem,
+            re.as_str()
+        )),
+    }
+}
+
+/// Permission bits of the file, like `644`, if the template uses `@mode`
+#[cfg(unix)]
+pub fn file_mode(templ: &NameTemplate, path: &Path) -> Result<Option<u32>, Box<dyn Error>> {
+    use std::os::unix::fs::PermissionsExt;
+    if !templ.has_parameter("@mode") {
+        return Ok(None);
+    }
+    Ok(Some(path.metadata()?.permissions().mode() & 0o777))
+}
+
+/// Permission bits are only available on unix
+#[cfg(not(unix))]
+pub fn file_mode(_templ: &NameTemplate, _path: &Path) -> Result<Option<u32>, Box<dyn Error>> {
+    Ok(None)
+}
+
+/// Parts of the old filename separated by the delimiter
+///
+/// With `brackets` the delimiters inside `[]` or `()` don't separate
+/// parts.
+pub fn stem_parts(stem: &str, brackets: bool, delimiter: char) -> Vec<&str> {
+    if !brackets {
+        return stem.split(delimiter).collect();
+    }
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut last = 0;
+    for (i, c) in stem.char_indices() {
+        match c {
+            '[' | '(' => depth += 1,
+            ']' | ')' => depth = depth.saturating_sub(1),
+            c if c == delimiter && depth == 0 => {
+                parts.push(&stem[last..i]);
+                last = i + c.len_utf8();
+            }
+            _ => (),
+        }
+    }
+    parts.push(&stem[last..]);
+    parts
+}
+
+/// English ordinal for the number (1st, 2nd, 3rd, 4th, ...)
+pub fn ordinal(num: usize) -> String {
+    let suffix = match (num % 10, num % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{}{}", num, suffix)
+}
+
+/// Splits the variable into its name and the modifier after `:`
+pub fn split_modifier(v: &str) -> (&str, Option<&str>) {
+    match v.split_once(':') {
+        Some((name, modifier)) => (name, Some(modifier)),
+        None => (v, None),
+    }
+}
+
+/// Modifier for the value of a variable, given after `:`
+#[derive(Clone, Copy, PartialEq)]
+pub enum Modifier {
+    /// First N characters, `abbrevN` or `N`
+    Abbrev(usize),
+    /// Pad to the width at the start (`>N`) or the end (`<N`), with
+    /// `-` or the character given before them (e.g. `0>5`)
+    Pad {
+        fill: char,
+        start: bool,
+        width: usize,
+    },
+    /// All lowercase, `lower`
+    Lower,
+    /// All uppercase, `upper`
+    Upper,
+    /// First letter of each word in uppercase, `title`
+    Title,
+    /// Lowercase words separated by `-` without punctuation, `slug`
+    Slug,
+}
+
+impl std::str::FromStr for Modifier {
+    type Err = String;
+
+    fn from_str(modifier: &str) -> Result<Self, Self::Err> {
+        match modifier {
+            "lower" => return Ok(Self::Lower),
+            "upper" => return Ok(Self::Upper),
+            "title" => return Ok(Self::Title),
+            "slug" => return Ok(Self::Slug),
+            _ => (),
+        }
+        let abbrev = modifier.strip_prefix("abbrev").unwrap_or(modifier);
+        if let Ok(n) = abbrev.parse::<usize>() {
+            return Ok(Self::Abbrev(n));
+        }
+        let align = modifier
+            .char_indices()
+            .find(|(_, c)| *c == '>' || *c == '<')
+            .map(|(i, c)| (&modifier[..i], c, modifier[i + 1..].parse::<usize>()));
+        if let Some((fill, align, Ok(width))) = align {
+            let mut fill_chars = fill.chars();
+            let fill = match (fill_chars.next(), fill_chars.next()) {
+                (None, _) => '-',
+                (Some(c), None) => c,
+                _ => return Err(format!("invalid fill in modifier {:?}", modifier)),
+            };
+            return Ok(Self::Pad {
+                fill,
+                start: align == '>',
+                width,
+            });
+        }
+        Err(format!(
+            "unknown modifier {:?}, use abbrevN, N, >N, <N, lower, upper, title or slug",
+            modifier
+        ))
+    }
+}
+
+impl Modifier {
+    /// Applies the modifier to the value of a variable
+    pub fn apply(&self, val: &str) -> String {
+        match self {
+            Self::Abbrev(n) => val.chars().take(*n).collect(),
+            Self::Pad { fill, start, width } => {
+                let padding: String =
+                    std::iter::repeat_n(*fill, width.saturating_sub(val.chars().count())).collect();
+                if *start {
+                    format!("{}{}", padding, val)
+                } else {
+                    format!("{}{}", val, padding)
+                }
+            }
+            Self::Lower => val.to_lowercase(),
+            Self::Upper => val.to_uppercase(),
+            Self::Title => {
+                let mut new_word = true;
+                val.chars()
+                    .flat_map(|c| {
+                        let upper = new_word;
+                        new_word = c.is_whitespace() || c == '-' || c == '_';
+                        if upper {
+                            c.to_uppercase().collect::<Vec<char>>()
+                        } else {
+                            c.to_lowercase().collect()
+                        }
+                    })
+                    .collect()
+            }
+            Self::Slug => val
+                .to_lowercase()
+                .split(|c: char| c.is_whitespace() || c == '-' || c == '_')
+                .map(|w| {
+                    w.chars()
+                        .filter(|c| c.is_alphanumeric())
+                        .collect::<String>()
+                })
+                .filter(|w| !w.is_empty())
+                .collect::<Vec<String>>()
+                .join("-"),
+        }
+    }
+}
+
+/// Applies the modifier (if any) to the value of a variable
+pub fn apply_modifier(val: &str, modifier: Option<&str>) -> Result<String, Box<dyn Error>> {
+    match modifier {
+        Some(m) => Ok(m.parse::<Modifier>()?.apply(val)),
+        None => Ok(val.to_string()),
+    }
+}
+
+/// Values of the variables that have a path separator in them
+///
+/// A separator would put the file in a subdirectory, the index of the
+/// part is given with the variable.
+pub fn separator_parts<'t>(templ: &NameTemplate<'t>, parts: &[String]) -> Vec<(usize, &'t str)> {
+    parts
+        .iter()
+        .zip(&templ.parts)
+        .enumerate()
+        .filter_map(|(i, (p, t))| match t {
+            NamePart::Variable(v) if p.contains(std::path::is_separator) => Some((i, *v)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// State of a batch for the names that depend on the earlier files
+#[derive(Default)]
+pub struct Batch {
+    /// Last value and the section number for the `#when:VAR` parameters
+    pub sections: HashMap<String, (Option<String>, usize)>,
+    /// Number of files in each directory for `#dir`
+    pub dir_numbers: HashMap<PathBuf, usize>,
+    /// Last number given by `@daily`, set from the history to continue it
+    pub daily: Option<DailyCounter>,
+    /// New names given in the batch
+    pub names: HashSet<PathBuf>,
+    /// Files moved away during the batch, their names can be reused
+    pub vacated: HashSet<PathBuf>,
+}
+
+impl Batch {
+    /// Fills in the parameters that depend on the earlier files
+    ///
+    /// These are `#when:VAR` from the value of the variable in
+    /// `values`, `@daily`, and `#dir` for the files in `dir`.
+    pub fn fill(
+        &mut self,
+        templ: &NameTemplate,
+        parts: &mut [String],
+        values: &HashMap<String, String>,
+        dir: &Path,
+    ) -> Result<(), Box<dyn Error>> {
+        // same number for every `@daily` or `#dir` in the name
+        let (mut daily_num, mut dir_num) = (None, None);
+        for (p, t) in parts.iter_mut().zip(&templ.parts) {
+            let NamePart::Parameter(param) = t else {
+                continue;
+            };
+            if let Some(var) = param.strip_prefix("#when:") {
+                let val = values
+                    .get(var)
+                    .ok_or_else(|| format!("Variable {:?} has no value", var))?;
+                let (prev, num) = self.sections.entry(var.to_string()).or_insert((None, 0));
+                if prev.as_ref() != Some(val) {
+                    *num += 1;
+                    *prev = Some(val.clone());
+                }
+                *p = num.to_string();
+            } else if *param == "@daily" {
+                let num = *daily_num.get_or_insert_with(|| {
+                    let today = Local::now().format("%F").to_string();
+                    let num = match &self.daily {
+                        Some(d) if d.date == today => d.last + 1,
+                        _ => 1,
+                    };
+                    self.daily = Some(DailyCounter {
+                        date: today,
+                        last: num,
+                    });
+                    num
+                });
+                *p = num.to_string();
+            } else if *param == "#dir" {
+                let num = *dir_num.get_or_insert_with(|| {
+                    let num = self.dir_numbers.entry(dir.to_path_buf()).or_insert(0);
+                    *num += 1;
+                    *num
+                });
+                *p = num.to_string();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Plans the new names for a batch of files from a format
+///
+/// Only the given values are used for the variables, nothing is asked
+/// for and nothing is renamed. The command line tool shares the steps
+/// that finish a name (`#when:`, `#dir`, `@daily`, the checks and the
+/// `-N` suffixes), but the options that change the numbers or the dates
+/// (e.g. `--date-from` or `--continue`) are only in the command line
+/// tool. Files that would get the same name are an error here.
+pub struct Planner<'a> {
+    pub template: NameTemplate<'a>,
+    /// Values for the variables in the format
+    pub values: HashMap<String, String>,
+    pub counter: Counter,
+    /// Directory to put the files in instead of their current one
+    pub destination: Option<PathBuf>,
+    /// Action for the operations
+    pub action: Action,
+    /// Transliterate the non ASCII characters
+    pub ascii: bool,
+    /// Make the name lowercase
+    pub lower: bool,
+    /// Make the extension lowercase
+    pub lower_ext: bool,
+    /// Keep the delimiters inside brackets when splitting the old name
+    pub split_brackets: bool,
+    /// Date used for the date time parameters
+    pub date: DateTime<Local>,
+    /// Locale for the month and day names in dates
+    pub locale: Locale,
+    /// Variable whose value is the subdirectory for the files
+    pub group_by: Option<String>,
+    /// Add `-2`, `-3` and so on to the names used earlier in the batch
+    pub suffix_collisions: bool,
+    /// Like `suffix_collisions`, but the existing files are also avoided
+    pub unique: bool,
+    /// Use the format in the `.nameit` sidecar of a file if it has one
+    pub sidecars: bool,
+    /// Extension of the metadata files used by `@meta:N`
+    pub meta_ext: String,
+}
+
+impl<'a> Planner<'a> {
+    /// Planner to rename the files with the default options
+    pub fn new(template: NameTemplate<'a>, values: HashMap<String, String>) -> Self {
+        Self {
+            template,
+            values,
+            counter: Counter::default(),
+            destination: None,
+            action: Action::Rename,
+            ascii: false,
+            lower: false,
+            lower_ext: false,
+            split_brackets: false,
+            date: Local::now(),
+            locale: Locale::POSIX,
+            group_by: None,
+            suffix_collisions: false,
+            unique: false,
+            sidecars: false,
+            meta_ext: "meta".to_string(),
+        }
+    }
+
+    /// Format from the sidecar of the file, if the sidecars are used
+    pub fn sidecar_format(&self, path: &Path) -> Result<Option<String>, Box<dyn Error>> {
+        if !self.sidecars {
+            return Ok(None);
+        }
+        read_sidecar(path)
+    }
+
+    /// Subdirectory for the file from the value of `group_by`
+    ///
+    /// It is made inside the destination, or the directory of the file.
+    pub fn group_dir(
+        &self,
+        path: &Path,
+        values: &HashMap<String, String>,
+        destination: Option<&Path>,
+    ) -> Result<Option<PathBuf>, Box<dyn Error>> {
+        let Some(var) = &self.group_by else {
+            return Ok(None);
+        };
+        let val = values
+            .get(var)
+            .ok_or_else(|| format!("Variable {:?} has no value", var))?;
+        let dir = destination.or(path.parent()).unwrap_or(Path::new(""));
+        Ok(Some(dir.join(sanitize(val, self.ascii, self.lower))))
+    }
+
+    /// Name (without extension) from the rendered parts of the template
+    ///
+    /// Errors if nothing but the separators is left after cleaning it
+    /// up with [`sanitize`].
+    pub fn file_name(
+        &self,
+        path: &Path,
+        templ: &NameTemplate,
+        parts: &[String],
+    ) -> Result<String, Box<dyn Error>> {
+        let name = sanitize(&parts.join(""), self.ascii, self.lower);
+        let blank = |c: char| c.is_whitespace() || c == '_' || c == '-' || c == templ.delimiter;
+        if !name.trim_matches(blank).is_empty() {
+            return Ok(name);
+        }
+        let empty: Vec<String> = parts
+            .iter()
+            .zip(&templ.parts)
+            .filter_map(|(p, t)| match t {
+                NamePart::Variable(v) | NamePart::Parameter(v) if p.trim().is_empty() => {
+                    Some(v.to_string())
+                }
+                NamePart::Capture(spec, ..) if p.trim().is_empty() => Some(spec.to_string()),
+                _ => None,
+            })
+            .collect();
+        Err(format!(
+            "Empty name for {:?}, these parts resolved empty: {:?}",
+            path, empty
+        )
+        .into())
+    }
+
+    /// New path for the file with the name, in the given directory
+    ///
+    /// With `suffix_collisions` or `unique` a name that is already
+    /// taken gets a `-N` suffix, the `N` is also given.
+    pub fn target(
+        &self,
+        batch: &Batch,
+        path: &Path,
+        name: &str,
+        dir: Option<&Path>,
+    ) -> (PathBuf, Option<usize>) {
+        let target = |name: &str| target_path(path, name, dir, self.lower_ext);
+        let taken = |new: &PathBuf| {
+            batch.names.contains(new)
+                || self.unique
+                    && new.as_path() != path
+                    && new.symlink_metadata().is_ok()
+                    && !batch.vacated.contains(new)
+        };
+        let new_name = target(name);
+        if !(self.suffix_collisions || self.unique) || !taken(&new_name) {
+            return (new_name, None);
+        }
+        let mut n = 2;
+        while taken(&target(&format!("{}-{}", name, n))) {
+            n += 1;
+        }
+        (target(&format!("{}-{}", name, n)), Some(n))
+    }
+
+    /// New path for the file with the number `num` in a batch of `total`
+    ///
+    /// The warnings are for the parts left empty (like a regex that
+    /// doesn't match) and the values with path separators.
+    pub fn render(
+        &self,
+        path: &Path,
+        num: usize,
+        total: usize,
+    ) -> Result<(PathBuf, Vec<String>), Box<dyn Error>> {
+        self.render_file(&mut Batch::default(), path, num, total)
+    }
+
+    /// New path for the file as part of the batch, with the warnings
+    fn render_file(
+        &self,
+        batch: &mut Batch,
+        path: &Path,
+        num: usize,
+        total: usize,
+    ) -> Result<(PathBuf, Vec<String>), Box<dyn Error>> {
+        let sidecar = self.sidecar_format(path)?;
+        let templ = match &sidecar {
+            Some(f) => NameTemplate::parse(f, self.template.delimiter)?,
+            None => self.template.clone(),
+        };
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let file = FileInfo {
+            stem: &stem,
+            num,
+            counter: &self.counter,
+            total,
+            mode: file_mode(&templ, path)?,
+            split_brackets: self.split_brackets,
+            delimiter: templ.delimiter,
+            meta: if templ.uses_meta() {
+                read_meta(path, &self.meta_ext)?
+            } else {
+                None
+            },
+            cid: content_id(&templ, path)?,
+            date: self.date,
+            locale: self.locale,
+        };
+        let mut parts = Vec::with_capacity(templ.parts.len());
+        let mut warnings = Vec::new();
+        let mut or_warn = |r: Result<String, String>| {
+            r.unwrap_or_else(|w| {
+                warnings.push(w);
+                String::new()
+            })
+        };
+        for p in &templ.parts {
+            parts.push(match p {
+                NamePart::Variable(v) => {
+                    let (var, modifier) = split_modifier(v);
+                    let val = self
+                        .values
+                        .get(var)
+                        .ok_or_else(|| format!("Variable {:?} has no value", var))?;
+                    apply_modifier(val, modifier)?
+                }
+                NamePart::Parameter(p) => or_warn(render_parameter(p, &file)),
+                NamePart::Capture(_, re, group) => or_warn(render_capture(re, *group, &file)),
+                NamePart::Delimiter(d) | NamePart::String(d) | NamePart::Value(d) => d.to_string(),
+            });
+        }
+        for (i, v) in separator_parts(&templ, &parts) {
+            warnings.push(format!(
+                "value {:?} for {:?} has a path separator",
+                parts[i], v
+            ));
+        }
+        let group = self.group_dir(path, &self.values, self.destination.as_deref())?;
+        let dir = group.as_deref().or(self.destination.as_deref());
+        let dir_of_file = dir.or(path.parent()).unwrap_or(Path::new(""));
+        batch.fill(&templ, &mut parts, &self.values, dir_of_file)?;
+        let name = self.file_name(path, &templ, &parts)?;
+        Ok((self.target(batch, path, &name, dir).0, warnings))
+    }
+
+    /// Operations for the files, numbered in the given order
+    ///
+    /// Errors if two of the files would get the same name, unless
+    /// `suffix_collisions` or `unique` is used.
+    pub fn plan(&self, paths: &[PathBuf]) -> Result<Vec<PlannedOperation>, Box<dyn Error>> {
+        let mut counter = self.counter.clone();
+        let mut batch = Batch::default();
+        let mut operations = Vec::with_capacity(paths.len());
+        for path in paths {
+            let (new_name, warnings) =
+                self.render_file(&mut batch, path, counter.next(path), paths.len())?;
+            if !batch.names.insert(new_name.clone()) {
+                return Err(format!("More than one file would be named {:?}", new_name).into());
+            }
+            if self.action != Action::Copy {
+                batch.vacated.insert(path.clone());
+            }
+            let mut op = PlannedOperation::new(path, &new_name, self.action);
+            op.warnings = warnings;
+            operations.push(op);
+        }
+        Ok(operations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn parse_error(fmt: &str) -> Option<TemplateParseError> {
+        NameTemplate::try_from(fmt).err()
+    }
+
+    /// Name for `old.txt` with the given values for the variables
+    fn render(fmt: &str, values: &[(&str, &str)]) -> String {
+        render_file("old.txt", fmt, values)
+    }
+
+    /// New name for the file with the given values for the variables
+    fn render_file(file: &str, fmt: &str, values: &[(&str, &str)]) -> String {
+        let values = values
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let planner = Planner::new(NameTemplate::try_from(fmt).unwrap(), values);
+        let (name, _) = planner.render(Path::new(file), 1, 1).unwrap();
+        name.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn template_errors() {
+        assert_eq!(
+            parse_error("{a{b}"),
+            Some(TemplateParseError::UnexpectedOpen(2))
+        );
+        assert_eq!(
+            parse_error("a}b"),
+            Some(TemplateParseError::UnexpectedClose(1))
+        );
+        assert_eq!(parse_error("a_{b"), Some(TemplateParseError::Unclosed(2)));
+        assert_eq!(
+            parse_error("a_{}"),
+            Some(TemplateParseError::EmptyVariable(2))
+        );
+    }
+
+    #[test]
+    fn modifiers() {
+        let val = "hello wORLD-foo_bar";
+        for (modifier, modified) in [
+            ("lower", "hello world-foo_bar"),
+            ("upper", "HELLO WORLD-FOO_BAR"),
+            ("title", "Hello World-Foo_Bar"),
+            ("slug", "hello-world-foo-bar"),
+            ("abbrev3", "hel"),
+            ("2", "he"),
+            ("0>22", "000hello wORLD-foo_bar"),
+            ("<21", "hello wORLD-foo_bar--"),
+            ("<5", val),
+        ] {
+            assert_eq!(apply_modifier(val, Some(modifier)).unwrap(), modified);
+        }
+        assert_eq!(
+            apply_modifier("Rock, Paper & Co.", Some("slug")).unwrap(),
+            "rock-paper-co"
+        );
+        assert_eq!(
+            render("a:upper_b:0>5", &[("a", "x"), ("b", "42")]),
+            "X_00042.txt"
+        );
+        assert_eq!(
+            parse_error("a_b:foo"),
+            Some(TemplateParseError::UnknownModifier(
+                4,
+                "unknown modifier \"foo\", use abbrevN, N, >N, <N, lower, upper, title or slug"
+                    .to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn unknown_parameters() {
+        for (fmt, pos, param) in [
+            ("@foo", 0, "@foo"),
+            ("x_#a", 2, "#a"),
+            ("*~x", 0, "*~x"),
+            ("@meta:x", 0, "@meta:x"),
+            ("#a:group", 0, "#a:group"),
+        ] {
+            assert_eq!(
+                parse_error(fmt),
+                Some(TemplateParseError::UnknownParameter(pos, param.to_string()))
+            );
+        }
+        assert!(parse_error("##_#o_*~2_@meta:1_#when:a_@total:group.").is_none());
+        assert!(parse_error("#when:group_#when:grouping_###:group").is_none());
+        assert_eq!(
+            parse_error("x_#:group--"),
+            Some(TemplateParseError::GroupSeparator(9, "--".to_string()))
+        );
+    }
+
+    #[test]
+    fn escaped_braces() {
+        let values = [("a", "1"), ("b", "2"), ("c", "3")];
+        assert_eq!(render("a{{b}}c", &values), "1{2}3.txt");
+        assert_eq!(render("{{a}}", &values), "{1}.txt");
+        assert_eq!(render("{real}_{{a}}", &values), "real_{1}.txt");
+        assert_eq!(render("{{{draft}}}", &values), "{draft}.txt");
+    }
+
+    #[test]
+    fn captures() {
+        let file = "Show.S01E07.720p.mkv";
+        assert_eq!(render_file(file, "{/S\\d+E\\d+/}", &[]), "S01E07.mkv");
+        assert_eq!(render_file(file, "{/S(\\d+)E(\\d+)/}", &[]), "01.mkv");
+        assert_eq!(render_file(file, "{/S(\\d+)E(\\d+)/2}", &[]), "07.mkv");
+        assert_eq!(render_file(file, "{/S(\\d+)E(\\d+)/0}", &[]), "S01E07.mkv");
+        assert_eq!(
+            render_file(file, "{/^(\\w+)/}_{/(\\d+)p/}", &[]),
+            "Show_720.mkv"
+        );
+        // nothing matches so the part is empty
+        assert_eq!(render_file(file, "{/x(\\d+)/}_{ep}", &[]), "_ep.mkv");
+        let planner = Planner::new(
+            NameTemplate::try_from("{/x(\\d+)/}_#").unwrap(),
+            HashMap::new(),
+        );
+        let (_, warnings) = planner.render(Path::new(file), 1, 1).unwrap();
+        assert_eq!(
+            warnings,
+            ["\"Show.S01E07.720p\" doesn't match the regex \"x(\\\\d+)\""]
+        );
+        assert!(matches!(
+            parse_error("{/S(\\d+)/2}"),
+            Some(TemplateParseError::InvalidRegex(0, _))
+        ));
+    }
+
+    #[test]
+    fn dot_delimiter() {
+        let templ = NameTemplate::parse("show.**.*~1.#", '.').unwrap();
+        let values = HashMap::from([("show".to_string(), "x y".to_string())]);
+        let planner = Planner::new(templ, values);
+        let (name, _) = planner.render(Path::new("a.b.c.txt"), 3, 3).unwrap();
+        assert_eq!(name, Path::new("x-y.a.b.c.3.txt"));
+    }
+
+    #[test]
+    fn history_delimiters() {
+        let mut hist = History {
+            formats: vec!["show-#".to_string(), "a_b".to_string()],
+            ..Default::default()
+        };
+        hist.set_delimiter("show-#", '-');
+        hist.set_delimiter("a_b", '_');
+        let json = serde_json::to_string(&hist).unwrap();
+        let hist: History = serde_json::from_str(&json).unwrap();
+        assert_eq!(hist.format_delimiter("show-#", '_'), Ok('-'));
+        assert_eq!(hist.format_delimiter("show-#", '-'), Ok('-'));
+        assert_eq!(hist.format_delimiter("a_b", '_'), Ok('_'));
+        assert!(hist.format_delimiter("show-#", '.').is_err());
+        assert!(hist.format_delimiter("a_b", '-').is_err());
+        let templ = NameTemplate::parse("show-#", '-').unwrap();
+        let values = HashMap::from([("show".to_string(), "x".to_string())]);
+        let name = Planner::new(templ, values).render(Path::new("old.txt"), 1, 1);
+        assert_eq!(name.unwrap().0, Path::new("x-1.txt"));
+        // histories from before the delimiters were saved
+        let hist: History = serde_json::from_str(r#"{"formats":["a_b"]}"#).unwrap();
+        assert_eq!(hist.format_delimiter("a_b", '_'), Ok('_'));
+    }
+
+    #[test]
+    fn percent_literals() {
+        assert!(is_date_format("%Y-%m-%d"));
+        assert!(!is_date_format("%"));
+        assert!(!is_date_format("%_off"));
+        assert_eq!(render("{50}%_{off}", &[]), "50%_off.txt");
+        assert_eq!(render("a_%", &[("a", "50")]), "50_%.txt");
+        let mut planner = Planner::new(
+            NameTemplate::try_from("%Y%m%d_{100}%").unwrap(),
+            HashMap::new(),
+        );
+        planner.date = Local.with_ymd_and_hms(2024, 5, 6, 0, 0, 0).unwrap();
+        let (name, _) = planner.render(Path::new("old.txt"), 1, 1).unwrap();
+        assert_eq!(name, Path::new("20240506_100%.txt"));
+    }
+
+    #[test]
+    fn counter() {
+        let mut counter = Counter {
+            start: 5,
+            step: 5,
+            ..Default::default()
+        };
+        let numbers: Vec<usize> = (0..3).map(|_| counter.next(Path::new("a.txt"))).collect();
+        assert_eq!(numbers, [5, 10, 15]);
+        assert_eq!(counter.format(7, 3), "007");
+        assert_eq!(counter.format(1234, 2), "1234");
+        let counter = Counter {
+            width: 4,
+            ..Default::default()
+        }
+        .with_base(16)
+        .unwrap();
+        assert_eq!(counter.format(255, 1), "00ff");
+        assert_eq!(counter.format(0, 1), "0000");
+        assert_eq!(counter.base(), 16);
+        for base in [0, 1, 37] {
+            assert!(Counter::default().with_base(base).is_err());
+        }
+        let mut counter = Counter {
+            reset: CounterReset::Extension,
+            ..Default::default()
+        };
+        let numbers: Vec<usize> = ["a.jpg", "b.png", "c.jpg", "d.png", "e.jpg"]
+            .iter()
+            .map(|p| counter.next(Path::new(p)))
+            .collect();
+        assert_eq!(numbers, [1, 1, 2, 2, 3]);
+    }
+
+    #[test]
+    fn lower_stem_only() {
+        let photo = Path::new("PHOTO.JPG");
+        let name = sanitize("PHOTO", false, true);
+        assert_eq!(
+            target_path(photo, &name, None, false),
+            Path::new("photo.JPG")
+        );
+        assert_eq!(
+            target_path(photo, &name, None, true),
+            Path::new("photo.jpg")
+        );
+        let name = sanitize("Café Noir", true, true);
+        let dest = Some(Path::new("out"));
+        assert_eq!(
+            target_path(Path::new("in/PHOTO.JPG"), &name, dest, false),
+            Path::new("out/cafe-noir.JPG")
+        );
+        // only the last extension is kept
+        assert_eq!(
+            target_path(Path::new("A.TAR.GZ"), "b", None, true),
+            Path::new("b.gz")
+        );
+    }
+
+    #[test]
+    fn plan() {
+        let values = HashMap::from([("show".to_string(), "x".to_string())]);
+        let paths = ["nameit-missing/a.mkv", "nameit-missing/b.mkv"].map(PathBuf::from);
+        let planner = Planner::new(NameTemplate::try_from("show_##").unwrap(), values.clone());
+        // the files don't need to exist
+        let plan = planner.plan(&paths).unwrap();
+        let names: Vec<&Path> = plan.iter().map(|op| op.destination.as_path()).collect();
+        assert_eq!(
+            names,
+            [
+                Path::new("nameit-missing/x_01.mkv"),
+                Path::new("nameit-missing/x_02.mkv")
+            ]
+        );
+        assert_eq!((plan[0].bytes, plan[0].modified), (None, None));
+        assert_eq!(plan[1].action, Action::Rename);
+        let mut planner = Planner::new(NameTemplate::try_from("show").unwrap(), values);
+        let err = planner.plan(&paths).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "More than one file would be named \"nameit-missing/x.mkv\""
+        );
+        planner.suffix_collisions = true;
+        let plan = planner.plan(&paths).unwrap();
+        assert_eq!(plan[1].destination, Path::new("nameit-missing/x-2.mkv"));
+    }
+
+    #[test]
+    fn stable_content_id() {
+        let path = std::env::temp_dir().join(format!("nameit-cid-{}", std::process::id()));
+        File::create(&path).unwrap();
+        let templ = NameTemplate::try_from("@cid").unwrap();
+        let hash = content_hash(&path).unwrap();
+        let id = content_id(&templ, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // XXH64 of the empty input
+        assert_eq!(hash, 0xef46db3751d8e999);
+        assert_eq!(id.as_deref(), Some("55dnwn2r3d"));
+    }
+}
@@ -1,123 +1,173 @@
-use chrono::Local;
-use clap::{ArgGroup, Parser};
+use chrono::{DateTime, Local, Locale, NaiveDate, TimeZone};
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
 use colored::Colorize;
 use directories::ProjectDirs;
+use exif::{In, Tag};
+use nameit::{
+    apply_modifier, content_hash, content_id, file_mode, file_stamp, is_parameter, read_history,
+    read_meta, render_capture, render_parameter, sanitize, save_history, separator_parts,
+    split_modifier, Action, Batch, Counter, CounterReset, FileInfo, History, NamePart,
+    NameTemplate, PlannedOperation, Planner,
+};
 use nu_term_grid::grid;
 use number_range::NumberRangeOptions;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
 use std::error::Error;
 use std::fs::File;
-use std::io::Write;
+use std::io::{BufRead, IsTerminal, Write};
 use std::io::{BufReader, BufWriter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use std::{
-    collections::{HashMap, HashSet},
-    path::PathBuf,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    path::{Path, PathBuf},
 };
 use terminal_size::{terminal_size, Width};
 
-#[derive(Clone)]
-enum NamePart<'a> {
-    String(&'a str),
-    Variable(&'a str),
-    Parameter(&'a str),
-    Delimiter(&'a str),
-}
-
-#[derive(Clone)]
-struct NameTemplate<'a> {
-    parts: Vec<NamePart<'a>>,
-}
-
-impl<'a> From<&'a str> for NameTemplate<'a> {
-    fn from(st: &'a str) -> Self {
-        let mut var_parts = Vec::<NamePart>::new();
-        let mut last: usize = 0;
-        let mut flag = false;
-        for (i, c) in st.chars().enumerate() {
-            match (c, flag) {
-                ('{', false) => {
-                    if i != last {
-                        var_parts.push(NamePart::Variable(&st[last..i]));
-                    }
-                    last = i + 1;
-                    flag = true;
-                }
-                ('{', true) => panic!("Invalid Format: unexpected '{{'"),
-                ('}', true) => {
-                    if i != last {
-                        var_parts.push(NamePart::String(&st[last..i]));
-                    }
-                    last = i + 1;
-                    flag = false;
-                }
-                ('}', false) => panic!("Invalid Format: unexpected '}}'"),
-                ('_', false) => {
-                    if i != last {
-                        var_parts.push(NamePart::Variable(&st[last..i]));
-                    }
-                    var_parts.push(NamePart::Delimiter("_"));
-                    last = i + 1;
-                }
-                _ => (),
-            }
-        }
-        if flag {
-            panic!("Invalid Format: Unclosed '{{'")
-        }
-        if last != st.len() {
-            var_parts.push(NamePart::Variable(&st[last..]));
-        }
+#[derive(Clone, Copy, ValueEnum)]
+enum ScriptKind {
+    /// POSIX shell
+    Sh,
+    /// Powershell
+    Ps1,
+}
 
-        let parts = var_parts
-            .into_iter()
-            .map(|var| {
-                if let NamePart::Variable(v) = var {
-                    if "%*?#".contains(v.chars().next().expect("Empty Variable")) {
-                        NamePart::Parameter(v)
-                    } else {
-                        var
-                    }
-                } else {
-                    var
-                }
-            })
-            .collect();
-        Self { parts }
-    }
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum DateSource {
+    /// Current time
+    Now,
+    /// Last modification time of the file
+    Mtime,
+    /// Creation time of the file
+    Ctime,
+    /// Date the photo was taken from the EXIF metadata
+    Exif,
 }
 
-impl ToString for NameTemplate<'_> {
-    fn to_string(&self) -> String {
-        self.parts
-            .iter()
-            .map(|p| match p {
-                NamePart::String(s) => s.to_string(),
-                NamePart::Delimiter(d) => d.to_string(),
-                NamePart::Variable(v) => format!("{}", v.on_blue()),
-                NamePart::Parameter(v) => format!("{}", v.on_yellow()),
-            })
-            .collect::<Vec<String>>()
-            .join("")
+#[derive(Clone, Copy, ValueEnum)]
+enum NumberAt {
+    /// Number before the current name
+    Prefix,
+    /// Number after the current name
+    Suffix,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum OnConflict {
+    /// Ask before replacing the file
+    Ask,
+    /// Skip the file
+    Skip,
+    /// Replace the file
+    Replace,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Reflink {
+    /// Use reflinks when the filesystem supports them, or copy
+    Auto,
+    /// Only use reflinks, error if the filesystem doesn't support them
+    Always,
+    /// Always copy the contents
+    Never,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DedupePolicy {
+    /// Skip the duplicate files
+    Skip,
+    /// Add a copy suffix to the duplicate files
+    Suffix,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Render a name from JSON in stdin without touching any files
+    ///
+    /// The input is an object with the `template`, `filename`,
+    /// `values` of the variables and `index` for the number, the
+    /// output is an object with the `name` and the `warnings` for the
+    /// parts left empty, or the `error`.
+    Render(RenderArgs),
+}
+
+/// Options for the `render` subcommand
+#[derive(Args)]
+struct RenderArgs {
+    /// Character that separates the parts of the format
+    #[arg(long, default_value = "_")]
+    delimiter: char,
+    /// Minimum width of the number, padded with zeros
+    #[arg(long, default_value = "0")]
+    pad: usize,
+    /// Base of the number (2 to 36)
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u32).range(2..=36))]
+    base: u32,
+    /// Transliterate the name to ASCII characters
+    #[arg(long, action)]
+    ascii: bool,
+    /// Make the name lowercase
+    #[arg(long, action)]
+    lower: bool,
+    /// Make the extension lowercase
+    #[arg(long, action)]
+    lower_ext: bool,
+    /// Don't split the old filename on the delimiter inside `[]` or `()`
+    #[arg(long, action)]
+    bracket_aware: bool,
+    /// Locale for the month and day names in dates (e.g. `fr_FR`)
+    #[arg(long)]
+    locale: Option<String>,
+}
+
+/// Input for the `render` subcommand
+#[derive(Deserialize)]
+struct RenderInput {
+    template: String,
+    filename: PathBuf,
+    #[serde(default)]
+    values: HashMap<String, String>,
+    #[serde(default = "RenderInput::default_index")]
+    index: usize,
+}
+
+impl RenderInput {
+    fn default_index() -> usize {
+        1
     }
 }
 
 #[derive(Parser)]
 #[command(group = ArgGroup::new("action").required(false).multiple(false))]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// Format to rename the file in
     ///
     /// formats given in CLI are not saved in history, it helps when
     /// batch processing a list of files with similar format at once,
     /// use `###` character format for zero padded numbers. If not
-    /// given asks interactively.
+    /// given asks interactively. A file with a sidecar file (e.g.
+    /// `photo.jpg.nameit` for `photo.jpg`) uses the format written in
+    /// the sidecar instead of this one.
     #[arg(short, long)]
     format: Option<String>,
     /// Destination directory
     ///
     /// Move or Rename the file to the destination directory instead
-    /// of the current one.
+    /// of the current one. Can be given multiple times to copy the
+    /// files to all of them, which doesn't work with rename or move.
     #[arg(short, long)]
-    destination: Option<PathBuf>,
+    destination: Vec<PathBuf>,
+    /// Keep each file in its own directory
+    ///
+    /// The new name is always in the same directory as the original
+    /// file, this takes precedence over `--destination`.
+    #[arg(long, action)]
+    in_place: bool,
     /// Repeat Last choice
     ///
     /// Choose the first option for all the interactive choices. Be
@@ -128,9 +178,22 @@ struct Cli {
     /// from 1.
     #[arg(short, long, action)]
     last: bool,
+    /// Confirm the parsed template before processing the files
+    ///
+    /// Shows the template and a sample name for the first file, and
+    /// asks before touching anything. Skipped with `--last` or when
+    /// the input is not a terminal.
+    #[arg(long, action)]
+    confirm_format: bool,
     /// Replace a file if same name is generated
     #[arg(short = 'R', long, action)]
     replace: bool,
+    /// What to do when a file with the new name already exists
+    ///
+    /// Defaults to the `NAMEIT_ON_CONFLICT` environment variable if it
+    /// is set, or `ask` otherwise. `--replace` is same as `replace`.
+    #[arg(long, value_enum, conflicts_with = "replace")]
+    on_conflict: Option<OnConflict>,
     /// Rename given file instead of copying
     ///
     /// Only works for files in the same mount point, if you have
@@ -148,66 +211,705 @@ struct Cli {
     /// Edit saved choices
     ///
     /// Gives you interactive options to edit the choices. Use it to
-    /// permanently filter the options.
+    /// permanently filter the options, or type `o FROM TO` to move
+    /// an option to another position.
     #[arg(short, long, action)]
     edit: bool,
     /// Print the new filename and do nothing
     #[arg(short, long, action)]
     test: bool,
     /// Number of choices to show from history
-    #[arg(short, long, default_value = "20")]
-    choices: usize,
+    ///
+    /// Defaults to the `NAMEIT_CHOICES` environment variable if it is
+    /// set, or 20 otherwise.
+    #[arg(short, long)]
+    choices: Option<usize>,
+    /// Show the choices in the same line as the prompt
+    ///
+    /// Like `client: [1]acme [2]beta [0]new <1>:`, the usual grid is
+    /// still used when the choices don't fit in one line.
+    #[arg(long, action)]
+    compact_prompt: bool,
+    /// Separate number counter for each file extension
+    ///
+    /// The `#` numbering restarts from 1 for each extension, so all
+    /// the `.jpg` files get 1..N and all the `.png` files get 1..M
+    /// independent of each other, in the order they were given.
+    #[arg(long, action)]
+    per_extension_counter: bool,
+    /// First number for the `#` parameter
+    #[arg(long, default_value = "1")]
+    start: usize,
+    /// Increment of the number between files
+    #[arg(long, default_value = "1")]
+    step: usize,
+    /// Minimum width of the number, padded with zeros
+    ///
+    /// The number of `#` characters is used if it's larger.
+    #[arg(long, default_value = "0")]
+    pad: usize,
+    /// Base of the number (2 to 36)
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u32).range(2..=36))]
+    base: u32,
+    /// Only add a number at the end of the current names
+    ///
+    /// Same as the format `?_###` with the number of `#` enough for
+    /// the number of files in the batch.
+    #[arg(long, action, conflicts_with = "format")]
+    append_index_only: bool,
+    /// Add a number before or after the current names
+    ///
+    /// Same as the format `###_?` or `?_###` with the number of `#`
+    /// enough for the number of files in the batch.
+    #[arg(long, value_enum, conflicts_with_all = ["format", "append_index_only"])]
+    number_at: Option<NumberAt>,
+    /// Continue the numbering from the existing files
+    ///
+    /// The files in the destination (or the directory of the first
+    /// file) matching the format are checked, and the numbers start
+    /// after the largest one using the same zero padded width.
+    #[arg(long, action)]
+    r#continue: bool,
+    /// Renumber the files contiguously in their existing order
+    ///
+    /// The numbers already in the filenames (matching the format) are
+    /// used to sort the files, which then get numbers without gaps
+    /// using the same zero padded width.
+    #[arg(long, action, conflicts_with = "number_by_name")]
+    flat_number: bool,
+    /// Copy the new filename(s) to clipboard
+    ///
+    /// Only works with `--test`, the generated names are copied to
+    /// the system clipboard (one per line) instead of being applied.
+    /// On Linux it keeps running until something else is copied, as
+    /// the clipboard contents go away with the program.
+    #[arg(long, action, requires = "test")]
+    clipboard: bool,
+    /// Only process files with these extensions
+    ///
+    /// Comma separated list of extensions (e.g. `jpg,png`), files
+    /// with other extensions are skipped. Comparison is case
+    /// insensitive.
+    #[arg(long, value_delimiter = ',')]
+    only_ext: Vec<String>,
+    /// Assign numbers in natural order of the original names
+    ///
+    /// The files are still processed in the order given, but the `#`
+    /// numbers follow the natural sort of the filenames, so `img2`
+    /// gets a smaller number than `img10`.
+    #[arg(long, action)]
+    number_by_name: bool,
+    /// Validate the values of a variable with a regex
+    ///
+    /// Given as `VAR=REGEX` (e.g. `code='^\d{3}$'`), the regex is saved
+    /// in the history and any new value not matching it is rejected
+    /// when choosing. Use `VAR=` to remove the validation.
+    #[arg(long, value_name = "VAR=REGEX")]
+    validate: Vec<String>,
+    /// Ask each variable only once for the whole batch
+    ///
+    /// The values chosen for the first file are reused for the rest
+    /// of the files, only the special parameters (numbers, dates,
+    /// old filename parts) change between files.
+    #[arg(long, action)]
+    ask_once: bool,
+    /// Suggest a value for a variable from the old filename
+    ///
+    /// Given as `VAR=SOURCE`, where source is a special parameter
+    /// (e.g. `?` or `**`) or a regex (e.g. `\d{4}` for a year), the
+    /// suggestion is saved in the history and can be picked with `=`
+    /// when choosing. Use `VAR=` to remove the suggestion.
+    #[arg(long, value_name = "VAR=SOURCE")]
+    suggest: Vec<String>,
+    /// Label shown instead of the variable name when choosing
+    ///
+    /// Given as `VAR=LABEL` (e.g. `c='Client code'`), the label is
+    /// saved in the history. Use `VAR=` to remove the label.
+    #[arg(long, value_name = "VAR=LABEL")]
+    label: Vec<String>,
+    /// Use the values of another variable for a variable
+    ///
+    /// Given as `ALIAS=VAR` (e.g. `c=client`), the alias shares the
+    /// choices with the variable, and is saved in the history. Use
+    /// `ALIAS=` to remove the alias.
+    #[arg(long, value_name = "ALIAS=VAR")]
+    alias: Vec<String>,
+    /// Print the path of the history file and exit
+    #[arg(long, action)]
+    r#where: bool,
+    /// Print the last N operations done and exit
+    ///
+    /// The renames, moves and copies are recorded in a journal next
+    /// to the history file, this shows the latest of them.
+    #[arg(long, value_name = "N")]
+    recent: Option<usize>,
+    /// Undo the last batch of operations from the journal and exit
+    ///
+    /// A batch is all the operations of one run, a different batch
+    /// can be given by its id from `--recent`. Renames and moves are
+    /// moved back, and copies are removed. Nothing is done if any of
+    /// the files were changed since, and with `--test` the operations
+    /// are only shown.
+    #[arg(long, value_name = "BATCH", num_args = 0..=1, default_missing_value = "")]
+    undo: Option<String>,
+    /// Print the size of the history and exit
+    ///
+    /// Shows the number of formats, variables and values saved, and
+    /// the size of the history file.
+    #[arg(long, action)]
+    stats: bool,
+    /// Rewrite the history file in the current format and exit
+    ///
+    /// Fields added in the newer versions are filled with their
+    /// defaults, and the old file is kept with a `.bak` extension.
+    #[arg(long, action)]
+    migrate_history: bool,
+    /// Set the format used by `--last` instead of the latest one
+    ///
+    /// The default format is saved in the history, give an empty
+    /// string to remove it and go back to using the latest format.
+    #[arg(long, value_name = "FORMAT")]
+    set_default_format: Option<String>,
+    /// Limit the number of values saved for each variable
+    ///
+    /// The limit is saved in the history, and the oldest values are
+    /// dropped when saving. Give 0 to remove the limit.
+    #[arg(long, value_name = "N")]
+    max_values_per_var: Option<usize>,
+    /// Merge the saved values of a variable into another one
+    ///
+    /// The values of FROM are added after the values of INTO, and
+    /// FROM is removed from the history.
+    #[arg(long, num_args = 2, value_names = ["FROM", "INTO"])]
+    merge_variables: Option<Vec<String>>,
+    /// Date used for the date time parameters
+    ///
+    /// If the EXIF date is not available in a file, its modification
+    /// time is used instead.
+    #[arg(long, value_enum, default_value = "now")]
+    date_from: DateSource,
+    /// Locale for the month and day names in dates (e.g. `fr_FR`)
+    #[arg(long)]
+    locale: Option<String>,
+    /// Value for a variable
+    ///
+    /// Given as `VAR=VALUE`, the variable will use this value for all
+    /// the files without asking. These values are not saved in the
+    /// history. Only the first `=` is used to separate the variable,
+    /// and the value can be quoted to keep leading/trailing spaces
+    /// (e.g. `title="a = b "`).
+    #[arg(long, value_name = "VAR=VALUE")]
+    set: Vec<String>,
+    /// Take the values for the variables from the name of this file
+    ///
+    /// The name (without extension) is matched against the format,
+    /// and the parts for the variables are used as their values for
+    /// this run like `--set`, which takes precedence. Variables with
+    /// modifiers are not taken from it.
+    #[arg(long, value_name = "PATH")]
+    like: Option<PathBuf>,
+    /// Error instead of asking for variables not in the history
+    ///
+    /// Useful in scripts to catch typos in the format, the values for
+    /// new variables can still be given with `--set`.
+    #[arg(long, action)]
+    no_prompt_new: bool,
+    /// Choices for a variable from the output of a command
+    ///
+    /// Given as `VAR=COMMAND`, the command is run once for the batch
+    /// and each line of its output is added to the choices for the
+    /// variable. Only the chosen values are saved in the history.
+    /// Needs `--allow-exec`.
+    #[arg(long, value_name = "VAR=COMMAND")]
+    values_cmd: Vec<String>,
+    /// Allow running commands given in `--values-cmd`
+    #[arg(long, action)]
+    allow_exec: bool,
+    /// Add a suffix when a name is already used in the batch
+    ///
+    /// The first file keeps the name and the later ones with the same
+    /// name get `-2`, `-3` and so on added to them.
+    #[arg(long, action)]
+    suffix_collisions: bool,
+    /// Add a suffix when a name is used in the batch or already exists
+    ///
+    /// Like `--suffix-collisions` but the existing files are also
+    /// avoided, the files renamed earlier in the batch free their
+    /// names, so `--test` shows the same names a real run would use.
+    #[arg(long, action)]
+    unique: bool,
+    /// Show the paths relative to the current directory
+    ///
+    /// The paths are shown without quotes so they can be copied.
+    #[arg(long, action)]
+    relative: bool,
+    /// Only print the new path of a single file
+    ///
+    /// Prints the absolute path the file would be renamed to and
+    /// nothing else, without touching the file or the history. It
+    /// never asks for inputs, so the format has to be given with
+    /// `--format` (or `--last`) and the variables with `--set` (or
+    /// `--last`). The name is made like in a batch of one file, so
+    /// the sidecar formats, `--group-by` and `--unique` apply, but the
+    /// `@daily` number isn't saved.
+    #[arg(long, action)]
+    stdout: bool,
+    /// Transliterate the new names to ASCII characters
+    ///
+    /// Accents are stripped and other scripts are converted to their
+    /// closest ASCII representation (e.g. `café` becomes `cafe`).
+    #[arg(long, action)]
+    ascii: bool,
+    /// Make the new names lowercase
+    ///
+    /// Like `--ascii` it is only applied to the name, the extension is
+    /// kept as it is unless `--lower-ext` is also given.
+    #[arg(long, action)]
+    lower: bool,
+    /// Make the extensions of the new names lowercase
+    #[arg(long, action)]
+    lower_ext: bool,
+    /// Print a sample name for the format and exit
+    ///
+    /// Variables are replaced by placeholders, history and files are
+    /// not touched.
+    #[arg(long, value_name = "FORMAT")]
+    preview: Option<String>,
+    /// Placeholder used for variables in `--preview`
+    ///
+    /// `{}` is replaced by the name of the variable.
+    #[arg(long, default_value = "<{}>")]
+    placeholder: String,
+    /// Print the names for the sample filenames listed in a file
+    ///
+    /// Each line of the file is a sample filename, the `--format` is
+    /// rendered for each of them using placeholders for the variables
+    /// and nothing is renamed.
+    #[arg(long, value_name = "FILE", requires = "format")]
+    dry_run_samples: Option<PathBuf>,
+    /// Operate on the target of symlinks instead of the links
+    ///
+    /// The symlinks in the paths are resolved before doing anything,
+    /// so the target file is renamed, moved or copied.
+    #[arg(long, action, overrides_with = "no_follow_symlinks")]
+    follow_symlinks: bool,
+    /// Operate on the symlinks themselves
+    ///
+    /// Rename and move will move the link, while copy will create a
+    /// new symlink pointing to the same target at the new name
+    /// instead of copying the contents of the target.
+    #[arg(long, action, overrides_with = "follow_symlinks")]
+    no_follow_symlinks: bool,
+    /// Use reflinks (copy on write) for the copies and moves
+    ///
+    /// On filesystems that support them (btrfs, XFS, APFS) the new
+    /// file shares the contents with the old one until either is
+    /// changed, so large files are copied almost instantly without
+    /// using more space.
+    #[arg(long, value_enum, default_value = "auto")]
+    reflink: Reflink,
+    /// Maximum number of files to process
+    ///
+    /// The rest of the files are ignored, use it as a safety limit
+    /// when you are not sure how many files are given.
+    #[arg(long, value_name = "N")]
+    max_files: Option<usize>,
+    /// Skip the files whose new name already exists with same contents
+    ///
+    /// The size and then the contents of the files are compared, and
+    /// if they are identical nothing is done for that file. Useful
+    /// when re-running a partially completed batch. Works with
+    /// `--test` to see which files would be skipped.
+    #[arg(long, action)]
+    skip_identical: bool,
+    /// Print the operations as a shell script
+    ///
+    /// Only works with `--test`, the `mv`/`cp` commands (or their
+    /// powershell equivalent) are printed at the end so that you can
+    /// review and run them yourself.
+    #[arg(long, value_enum, requires = "test")]
+    script: Option<ScriptKind>,
+    /// Include the files inside the directories given in paths
+    ///
+    /// The directories are searched recursively, and the files are
+    /// processed in sorted order. Symlinks to directories are not
+    /// followed, and the sidecar files (`.nameit` and `--meta-ext`) of
+    /// the other files found are skipped.
+    #[arg(long, action)]
+    recursive: bool,
+    /// Print a table of the proposed names grouped by destination
+    ///
+    /// Only works with `--test`, so nothing is written; use with
+    /// `--recursive` to review the reorganization of a whole tree.
+    #[arg(long, action, requires = "test")]
+    audit: bool,
+    /// Only list the files whose name would change
+    ///
+    /// Only works with `--test`, the files that already have the new
+    /// name are not shown.
+    #[arg(long, action, requires = "test")]
+    changed_only: bool,
+    /// Don't ask for confirmation when the extension would change
+    ///
+    /// The extension is kept from the old file, but a file without an
+    /// extension can get one from the `.` in the new name.
+    #[arg(long, action)]
+    allow_ext_change: bool,
+    /// Print the command to repeat this run without prompts
+    ///
+    /// The format and the values chosen for the variables are given
+    /// in the command, along with the options for the action.
+    #[arg(long, action)]
+    emit_command: bool,
+    /// Treat the warnings about the format as errors
+    ///
+    /// For example a format without a number (`#`) or any other part
+    /// that changes between the files for a batch of files, or a
+    /// value with a path separator in it.
+    #[arg(long, action)]
+    strict: bool,
+    /// Put the files in subdirectories named by the variable's value
+    ///
+    /// The subdirectories are made inside the destination (or the
+    /// directory of the file), the variable is asked for if it is not
+    /// in the format. Can't be used with `--in-place` as the files are
+    /// moved out of their directory.
+    #[arg(long, value_name = "VAR", conflicts_with = "in_place")]
+    group_by: Option<String>,
+    /// Write a JSON report of the run to the file
+    ///
+    /// The report has the template, the options and the list of all
+    /// the operations with their status, size and errors if any.
+    #[arg(long, value_name = "PATH")]
+    summary_json: Option<PathBuf>,
+    /// Save the planned operations to a file to apply later
+    ///
+    /// Only works with `--test`, use `--apply-plan` with the file to
+    /// do the operations after reviewing them.
+    #[arg(long, value_name = "PATH", requires = "test")]
+    save_plan: Option<PathBuf>,
+    /// Do the operations saved with `--save-plan` and exit
+    ///
+    /// The format and variables are not used, and nothing is done if
+    /// any of the files have changed since the plan was saved. The new
+    /// names that exist are handled with `--on-conflict`, the names
+    /// freed by the earlier operations of the plan can be used.
+    #[arg(long, value_name = "PATH", conflicts_with = "test")]
+    apply_plan: Option<PathBuf>,
+    /// Set the modification times of the new files in the batch order
+    ///
+    /// The times start from the earliest modification time in the
+    /// batch and increase by a second for each file, so that sorting
+    /// by time and by the numbered names give the same order.
+    #[arg(long, action)]
+    touch_in_order: bool,
+    /// Modification time for the directories created for the files
+    ///
+    /// Use `files` for the latest modification time of the files
+    /// placed in the directory, or give a date as `YYYY-MM-DD` or
+    /// `YYYY-MM-DD HH:MM:SS`. Applies to the directories from
+    /// `--group-by` and `--ask-destination`.
+    #[arg(long, value_name = "files|DATE", value_parser = parse_dir_time)]
+    dir_time: Option<DirTime>,
+    /// Don't split the old filename on `_` inside `[]` or `()`
+    ///
+    /// For the `*` parameters, so that `2023_[client_x]_final` has
+    /// three parts instead of four.
+    #[arg(long, action)]
+    bracket_aware: bool,
+    /// Character that separates the parts of the format
+    ///
+    /// The old filename is also split on it for the `*` parameters.
+    /// For example with `-` the format `client-#` has the variable
+    /// `client` and a number.
+    #[arg(long, default_value = "_")]
+    delimiter: char,
+    /// Extension of the metadata files used by `@meta:N`
+    #[arg(long, default_value = "meta", value_name = "EXT")]
+    meta_ext: String,
+    /// Print how long the planning, prompting and execution took
+    ///
+    /// The times are printed to stderr at the end, along with the
+    /// average time per file. Prompting is the time waiting for the
+    /// inputs, and planning is the rest outside of the file operations.
+    #[arg(long, action)]
+    timings: bool,
+    /// Ask for the destination of each file
+    ///
+    /// The destinations are chosen from the ones used before or a new
+    /// one can be typed, which is created if it doesn't exist.
+    #[arg(long, action, conflicts_with_all = ["destination", "in_place"])]
+    ask_destination: bool,
+    /// Rhai expression that gives the new name (without extension)
+    ///
+    /// The format, the `.nameit` sidecars and the history are not used,
+    /// the expression can use the variables `name` (old name without
+    /// extension), `ext`, `index` (the number for `#`), `total` (number
+    /// of files), `size` (in bytes) and `date` (as `YYYY-MM-DD`), for
+    /// example `name.to_upper() + "_" + index`.
+    #[cfg(feature = "script")]
+    #[arg(long, value_name = "EXPR", conflicts_with = "format")]
+    script_expr: Option<String>,
+    /// Detect the files with duplicate contents in the batch
+    ///
+    /// With `skip` only the first of the duplicate files is processed,
+    /// with `suffix` the duplicates get the same name as the first
+    /// one with a `-copyN` suffix.
+    #[arg(long, value_enum, value_name = "POLICY")]
+    dedupe_by_hash: Option<DedupePolicy>,
     /// Paths to rename
     ///
     /// If you have more than one path then any number of character
     /// `#` in the format string will be replaced with the loop index
     /// (starting at 1), you can use that system to batch rename
-    /// files.
+    /// files. A file named `render` has to be given as `./render`, or
+    /// after `--`, to not be taken as the subcommand.
     paths: Vec<PathBuf>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct History {
-    formats: Vec<String>,
-    variables: HashSet<String>,
-    values: HashMap<String, Vec<String>>,
+/// Locale for the dates, English if not given or not supported
+fn parse_locale(locale: Option<&str>) -> Locale {
+    match locale {
+        Some(l) => Locale::try_from(l.replace('-', "_").as_str()).unwrap_or_else(|_| {
+            eprintln!(
+                "{}: unsupported locale {:?}, using English",
+                "Warning".on_yellow().bold(),
+                l
+            );
+            Locale::POSIX
+        }),
+        None => Locale::POSIX,
+    }
 }
 
-impl Default for History {
-    fn default() -> Self {
-        History {
-            formats: Vec::new(),
-            variables: HashSet::new(),
-            values: HashMap::new(),
+impl Cli {
+    /// Locale for the dates, English if not given or not supported
+    fn locale(&self) -> Locale {
+        parse_locale(self.locale.as_deref())
+    }
+
+    /// Number of choices to show, from the CLI or the environment
+    fn choices(&self) -> usize {
+        if let Some(c) = self.choices {
+            return c;
+        }
+        match std::env::var("NAMEIT_CHOICES") {
+            Ok(c) => c.trim().parse().unwrap_or_else(|_| {
+                eprintln!(
+                    "{}: invalid NAMEIT_CHOICES {:?}, using 20",
+                    "Warning".on_yellow().bold(),
+                    c
+                );
+                20
+            }),
+            Err(_) => 20,
+        }
+    }
+
+    /// Action for existing files, from the CLI or the environment
+    fn on_conflict(&self) -> OnConflict {
+        if self.replace {
+            return OnConflict::Replace;
+        }
+        if let Some(c) = self.on_conflict {
+            return c;
+        }
+        match std::env::var("NAMEIT_ON_CONFLICT") {
+            Ok(c) => OnConflict::from_str(&c, true).unwrap_or_else(|_| {
+                eprintln!(
+                    "{}: invalid NAMEIT_ON_CONFLICT {:?}, using ask",
+                    "Warning".on_yellow().bold(),
+                    c
+                );
+                OnConflict::Ask
+            }),
+            Err(_) => OnConflict::Ask,
+        }
+    }
+
+    /// Format given from the CLI for a batch of `count` files
+    fn cli_format(&self, count: usize) -> Option<String> {
+        let number = || {
+            let counter = self.counter();
+            let last = counter.start + count.saturating_sub(1) * counter.step;
+            "#".repeat(counter.format(last, 1).len())
+        };
+        match (self.append_index_only, self.number_at) {
+            (true, _) | (false, Some(NumberAt::Suffix)) => {
+                Some(format!("?{}{}", self.delimiter, number()))
+            }
+            (false, Some(NumberAt::Prefix)) => Some(format!("{}{}?", number(), self.delimiter)),
+            (false, None) => self.format.clone(),
         }
     }
+
+    /// Planner with the naming options from the CLI
+    fn planner<'a>(&self, templ: NameTemplate<'a>, values: HashMap<String, String>) -> Planner<'a> {
+        let mut planner = Planner::new(templ, values);
+        planner.counter = self.counter();
+        planner.ascii = self.ascii;
+        planner.lower = self.lower;
+        planner.lower_ext = self.lower_ext;
+        planner.split_brackets = self.bracket_aware;
+        planner.locale = self.locale();
+        planner.group_by = self.group_by.clone();
+        planner.suffix_collisions = self.suffix_collisions;
+        planner.unique = self.unique;
+        planner.sidecars = true;
+        planner.meta_ext = self.meta_ext.clone();
+        planner
+    }
+
+    /// Counter for the `#` parameter from the CLI options
+    fn counter(&self) -> Counter {
+        // the base is already checked when parsing the arguments
+        let mut counter = Counter::default().with_base(self.base).unwrap_or_default();
+        counter.start = self.start;
+        counter.step = self.step;
+        counter.width = self.pad;
+        counter.reset = if self.per_extension_counter {
+            CounterReset::Extension
+        } else {
+            CounterReset::Never
+        };
+        counter
+    }
 }
 
-fn save_history(fname: &PathBuf, history: &History) -> Result<(), Box<dyn Error>> {
-    let par = fname.parent().unwrap();
-    if !par.exists() {
-        std::fs::create_dir_all(&par)?;
+/// Rewrites the history file in the current format
+///
+/// The old file is kept as a backup with `.bak` added to its name.
+fn migrate_history(path: &PathBuf) -> Result<(), Box<dyn Error>> {
+    if !path.exists() {
+        println!("{}", "No history to migrate".yellow());
+        return Ok(());
+    }
+    let old: serde_json::Value = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    let mut hist = read_history(path)?;
+    // every variable with saved values should be listed
+    hist.variables.extend(hist.values.keys().cloned());
+    let mut backup = path.clone().into_os_string();
+    backup.push(".bak");
+    std::fs::copy(path, &backup)?;
+    save_history(path, &hist)?;
+    if let serde_json::Value::Object(new) = serde_json::to_value(&hist)? {
+        let added: Vec<&String> = new.keys().filter(|k| old.get(k).is_none()).collect();
+        if !added.is_empty() {
+            println!("{}: {:?}", "Added fields".green().bold(), added);
+        }
     }
-    let file = File::create(fname)?;
-    let writer = BufWriter::new(file);
-    serde_json::to_writer(writer, history)?;
+    println!(
+        "{}: backup saved to {:?}",
+        "Migrated".green().bold(),
+        backup
+    );
     Ok(())
 }
 
-fn read_history(path: &PathBuf) -> Result<History, Box<dyn Error>> {
-    let file = match File::open(path) {
-        Ok(f) => f,
-        Err(e) => {
-            if e.kind() == std::io::ErrorKind::NotFound {
-                return Ok(History::default());
-            } else {
-                return Err(Box::new(e));
-            }
+/// Parses the `VAR=VALUE` settings, empty value means remove the setting
+///
+/// Only the first `=` separates the variable and the value, so the
+/// value can have `=` in it. The value can be quoted with `"` or `'`
+/// to keep the spaces around it.
+fn parse_var_setting(opt: &str) -> Result<(&str, Option<&str>), Box<dyn Error>> {
+    let (var, val) = opt
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid setting {:?}, use VAR=VALUE", opt))?;
+    let val = val.trim();
+    let val = ['"', '\'']
+        .iter()
+        .find_map(|q| val.strip_prefix(*q).and_then(|v| v.strip_suffix(*q)))
+        .unwrap_or(val);
+    Ok((var.trim(), Some(val).filter(|v| !v.is_empty())))
+}
+
+/// Prints the formats and values that are in `new` but not in `old`
+///
+/// Returns whether anything was printed.
+fn print_new_entries(title: &str, old: &History, new: &History) -> bool {
+    let formats: Vec<&String> = new
+        .formats
+        .iter()
+        .filter(|f| !old.formats.contains(f))
+        .collect();
+    let values: Vec<(&String, Vec<&String>)> = new
+        .values
+        .iter()
+        .map(|(k, v)| {
+            let old_v = old.values.get(k);
+            (
+                k,
+                v.iter()
+                    .filter(|s| !old_v.map(|o| o.contains(s)).unwrap_or(false))
+                    .collect::<Vec<&String>>(),
+            )
+        })
+        .filter(|(_, v)| !v.is_empty())
+        .collect();
+    if formats.is_empty() && values.is_empty() {
+        return false;
+    }
+    println!("{}:", title.green().bold());
+    for f in formats {
+        println!("  {}: {}", "Format".yellow(), f);
+    }
+    for (k, v) in values {
+        println!(
+            "  {}: {}",
+            k.blue(),
+            v.iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        );
+    }
+    true
+}
+
+/// Width to show the choices in, `None` for a single column
+///
+/// When the terminal size is not known the `COLUMNS` environment
+/// variable is used, and when the output is not a terminal (e.g. piped
+/// to a file) the choices are shown one per line.
+fn choices_width() -> Option<usize> {
+    if let Some((Width(w), _)) = terminal_size() {
+        return Some(w.into());
+    }
+    if let Some(w) = std::env::var("COLUMNS").ok().and_then(|c| c.parse().ok()) {
+        return Some(w);
+    }
+    std::io::stdout().is_terminal().then_some(100)
+}
+
+/// Prints the choices in a grid that fits the terminal
+fn print_choices(vec: &[String], filter: bool, max_choice: usize, suggestion: Option<&str>) {
+    let mut grd = grid::Grid::new(grid::GridOptions {
+        filling: grid::Filling::Spaces(2),
+        direction: grid::Direction::LeftToRight,
+    });
+    if !filter {
+        grd.add(grid::Cell::from(format!(
+            "[0] {} ",
+            "<new entry>".bold().yellow()
+        )));
+    }
+    if let Some(sug) = suggestion {
+        grd.add(grid::Cell::from(format!("[=] {} ", sug.green())));
+    }
+
+    let mut i = 1;
+    for h in vec {
+        grd.add(grid::Cell::from(format!("[{}] {} ", i, h)));
+        i += 1;
+        if i > max_choice {
+            break;
         }
-    };
-    let reader = BufReader::new(file);
-    let hist = serde_json::from_reader(reader)?;
-    Ok(hist)
+    }
+    if let Some(g) = choices_width().and_then(|w| grd.fit_into_width(w)) {
+        println!("{}", g);
+    } else {
+        println!("{}", grd.fit_into_columns(1));
+    }
 }
 
 fn choose(
@@ -215,64 +917,100 @@ fn choose(
     vec: &mut Vec<String>,
     filter: bool,
     max_choice: usize,
+    validator: Option<&Regex>,
+    suggestion: Option<&str>,
+    compact: bool,
 ) -> Result<String, Box<dyn Error>> {
-    let mut manual = vec.len() == 0;
+    let mut manual = vec.is_empty();
     let mut buf = String::new();
     let mut choice: usize = 0;
-
-    if !manual {
-        println!("{} {}:", "Choices for".bold().blue(), prompt.bold().blue());
-        let mut grd = grid::Grid::new(grid::GridOptions {
-            filling: grid::Filling::Spaces(2),
-            direction: grid::Direction::LeftToRight,
-        });
-        if !filter {
-            grd.add(grid::Cell::from(format!(
-                "[0] {} ",
-                "<new entry>".bold().yellow()
-            )));
+    let is_valid = |value: &str| match validator {
+        Some(re) if !re.is_match(value) => {
+            eprintln!(
+                "{}: {:?} doesn't match the pattern {:?}",
+                "Error".red(),
+                value,
+                re.as_str()
+            );
+            false
         }
+        _ => true,
+    };
 
-        let mut i = 1;
-        for h in &mut *vec {
-            grd.add(grid::Cell::from(format!("[{}] {} ", i, h)));
-            i += 1;
-            if i > max_choice {
-                break;
-            }
-        }
-        let width: usize = if let Some((Width(w), _)) = terminal_size() {
-            w.into()
-        } else {
-            100
-        };
-        if let Some(g) = grd.fit_into_width(width) {
-            println!("{}", g);
-        } else {
-            println!("{}", grd.fit_into_columns(1));
-        }
+    if !manual {
         let def = if filter {
             format!("1-{}", vec.len())
         } else {
             "1".to_string()
         };
+        let mut select = format!("{} <{}>: ", "Select".on_blue().bold(), def);
+        // all the choices in the same line as the prompt if they fit
+        let mut inline: Vec<String> = vec
+            .iter()
+            .take(max_choice)
+            .enumerate()
+            .map(|(i, h)| format!("[{}]{}", i + 1, h))
+            .collect();
+        if let Some(sug) = suggestion {
+            inline.push(format!("[=]{}", sug));
+        }
+        if !filter {
+            inline.push("[0]new".to_string());
+        }
+        let inline = format!("{} <{}>: ", inline.join(" "), def);
+        let inline_width = prompt.chars().count() + 2 + inline.chars().count();
+        if compact && inline_width <= choices_width().unwrap_or(80) {
+            select = format!("{}: {}", prompt.bold().blue(), inline);
+        } else {
+            println!("{} {}:", "Choices for".bold().blue(), prompt.bold().blue());
+            print_choices(vec, filter, max_choice, suggestion);
+        }
         loop {
-            print!("{} <{}>: ", "Select".on_blue().bold(), def);
+            print!("{}", select);
             std::io::stdout().flush()?;
-            std::io::stdin().read_line(&mut buf)?;
+            read_input(&mut buf)?;
             match (buf.trim(), filter) {
                 ("", true) => return Ok(def),
                 ("", false) => choice = 0,
+                (b, true) if b.starts_with("o ") => {
+                    // reorder: move an item to another position
+                    let pos: Vec<usize> = b[2..]
+                        .split_whitespace()
+                        .filter_map(|n| n.parse().ok())
+                        .collect();
+                    match pos[..] {
+                        [from, to]
+                            if (1..=vec.len()).contains(&from) && (1..=vec.len()).contains(&to) =>
+                        {
+                            let item = vec.remove(from - 1);
+                            vec.insert(to - 1, item);
+                            let order: Vec<String> = vec
+                                .iter()
+                                .take(max_choice)
+                                .enumerate()
+                                .map(|(i, v)| format!("[{}] {}", i + 1, v))
+                                .collect();
+                            println!("{}", order.join("  "));
+                        }
+                        _ => eprintln!(
+                            "{}: use \"o FROM TO\" with positions from 1 to {}",
+                            "Error".red(),
+                            vec.len()
+                        ),
+                    }
+                    buf.clear();
+                    continue;
+                }
                 (b, true) => {
                     let choices: HashSet<usize> = NumberRangeOptions::default()
                         .with_list_sep(',')
                         .with_range_sep('-')
                         .with_default_start(1)
                         .with_default_end(vec.len())
-                        .parse(&b)?
+                        .parse(b)?
                         .collect();
                     let mut new_vec: Vec<String> = vec
-                        .into_iter()
+                        .iter_mut()
                         .enumerate()
                         .filter_map(|(i, f)| {
                             if choices.contains(&(i + 1)) {
@@ -287,20 +1025,81 @@ fn choose(
                     return Ok(buf);
                 }
                 (b, false) => {
-                    choice = match b.parse() {
+                    choice = match b.parse::<usize>() {
                         Ok(c) => {
                             if c > vec.len() {
                                 eprintln!("{}: Enter from 0 to {} only", "Error".red(), vec.len());
                                 buf.clear();
                                 continue;
+                            } else if c > 0 && !is_valid(&vec[c - 1]) {
+                                buf.clear();
+                                continue;
                             } else {
                                 c
                             }
                         }
                         Err(e) => {
-                            if b.starts_with("/") {
-                                vec.push(b[1..].trim().to_string());
+                            // not a letter, so it never picks a choice by its first letters
+                            if let (Some(sug), "=") = (suggestion, b) {
+                                if !is_valid(sug) {
+                                    buf.clear();
+                                    continue;
+                                }
+                                match vec.iter().position(|v| v == sug) {
+                                    Some(i) => i + 1,
+                                    None => {
+                                        vec.push(sug.to_string());
+                                        vec.len()
+                                    }
+                                }
+                            } else if let Some(new) = b.strip_prefix('/') {
+                                if !is_valid(new.trim()) {
+                                    buf.clear();
+                                    continue;
+                                }
+                                vec.push(new.trim().to_string());
                                 vec.len()
+                            } else if !b.starts_with(|c: char| c.is_ascii_digit()) {
+                                // first letters of the shown choices, or
+                                // else a part of any of the choices
+                                let prefix = b.to_lowercase();
+                                let find = |n: usize, f: &dyn Fn(&str) -> bool| -> Vec<usize> {
+                                    vec.iter()
+                                        .take(n)
+                                        .enumerate()
+                                        .filter(|(_, v)| f(&v.to_lowercase()))
+                                        .map(|(i, _)| i + 1)
+                                        .collect()
+                                };
+                                let mut matches = find(max_choice, &|v| v.starts_with(&prefix));
+                                if matches.is_empty() {
+                                    matches = find(vec.len(), &|v| v.contains(&prefix));
+                                }
+                                match matches[..] {
+                                    [c] if is_valid(&vec[c - 1]) => c,
+                                    [] => {
+                                        eprintln!("{}: No choices match {:?}", "Error".red(), b);
+                                        buf.clear();
+                                        continue;
+                                    }
+                                    [_] => {
+                                        buf.clear();
+                                        continue;
+                                    }
+                                    _ => {
+                                        let matched: Vec<String> = matches
+                                            .iter()
+                                            .map(|i| format!("[{}] {}", i, vec[i - 1]))
+                                            .collect();
+                                        eprintln!(
+                                            "{}: {}, type more letters",
+                                            "Ambiguous".yellow(),
+                                            matched.join("  ")
+                                        );
+                                        buf.clear();
+                                        continue;
+                                    }
+                                }
                             } else {
                                 eprintln!("{}: {:?}", "Error".red(), e.kind());
                                 buf.clear();
@@ -323,14 +1122,25 @@ fn choose(
         if filter {
             return Ok("0".to_string());
         }
-        print!(
-            "{}{}: ",
-            "Input ".on_bright_green().black().bold(),
-            prompt.on_bright_green().black().bold()
-        );
-        std::io::stdout().flush()?;
-        buf.clear();
-        std::io::stdin().read_line(&mut buf)?;
+        loop {
+            print!(
+                "{}{}: ",
+                "Input ".on_bright_green().black().bold(),
+                prompt.on_bright_green().black().bold()
+            );
+            if let Some(sug) = suggestion {
+                print!("<{}> ", sug);
+            }
+            std::io::stdout().flush()?;
+            buf.clear();
+            read_input(&mut buf)?;
+            if let (Some(sug), "") = (suggestion, buf.trim()) {
+                buf = sug.to_string();
+            }
+            if is_valid(buf.trim()) {
+                break;
+            }
+        }
         vec.push(buf.trim().to_string());
         choice = vec.len() - 1;
     }
@@ -340,217 +1150,2784 @@ fn choose(
     Ok(choice)
 }
 
-fn render_filename(
-    cur: &str,
-    hist: &mut History,
-    templ: NameTemplate,
-    num: usize,
-    last: bool,
-    max_choice: usize,
-) -> Result<Vec<String>, Box<dyn Error>> {
-    let vars: Vec<String> = templ
-        .parts
-        .into_iter()
-        .map(|p| {
-            match p {
-                NamePart::Variable(v) => match hist.values.get_mut(v) {
-                    Some(mut k) => {
-                        if last {
-                            Ok(k[0].clone())
-                        } else {
-                            choose(v, &mut k, false, max_choice)
-                        }
-                    }
-                    None => {
-                        hist.variables.insert(v.to_string());
-                        let mut newvec = vec![];
-                        // here since the variable is not new when --last
-                        // is used it won't happen, so I'll leave it be
-                        // interactive. Is manual format is given from
-                        // TUI, it'll need one time input.
-                        let var = choose(v, &mut newvec, false, max_choice);
-                        hist.values.insert(v.to_string(), newvec);
-                        var
-                    }
-                },
-                NamePart::Parameter(p) => {
-                    if p.chars().all(|c| c == '#') {
-                        Ok(format!("{0:01$}", num, p.len()))
-                    } else if p == "?" {
-                        Ok(cur.to_string())
-                    } else if p.starts_with("%") {
-                        Ok(Local::now().format(&p).to_string())
-                    } else if p.chars().all(|c| c == '*') {
-                        Ok(format!(
-                            "{}",
-                            cur.split("_")
-                                .take(p.len())
-                                .collect::<Vec<&str>>()
-                                .join("_")
-                        ))
-                    } else {
-                        panic!("Unexpected Special Parameter: {p}")
+/// Creates a new symlink at `dest` pointing to the target of `link`
+fn copy_symlink(link: &Path, dest: &Path) -> Result<(), Box<dyn Error>> {
+    let mut target = std::fs::read_link(link)?;
+    if target.is_relative() {
+        // relative targets won't be valid from a different directory
+        let par = match link.parent() {
+            Some(p) if p != Path::new("") => p,
+            _ => Path::new("."),
+        };
+        target = par.canonicalize()?.join(target);
+    }
+    if dest.symlink_metadata().is_ok() {
+        std::fs::remove_file(dest)?;
+    }
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(target, dest)?;
+    #[cfg(windows)]
+    std::os::windows::fs::symlink_file(target, dest)?;
+    Ok(())
+}
+
+/// Renames, moves or copies the file to the new name
+fn apply_operation(
+    filename: &Path,
+    new_name: &Path,
+    action: Action,
+    keep_symlinks: bool,
+    reflink: Reflink,
+) -> Result<(), Box<dyn Error>> {
+    let (filename, new_name) = (&long_path(filename)?, long_path(new_name)?);
+    if action == Action::Rename {
+        std::fs::rename(filename, new_name)?;
+    } else {
+        if keep_symlinks && filename.is_symlink() {
+            copy_symlink(filename, &new_name)?;
+        } else {
+            match reflink {
+                Reflink::Auto => {
+                    reflink::reflink_or_copy(filename, new_name)?;
+                }
+                Reflink::Always => {
+                    // reflink won't replace an existing file, so it's
+                    // made next to it and then renamed into place
+                    let mut temp = new_name.clone().into_os_string();
+                    temp.push(".nameit-tmp");
+                    let temp = PathBuf::from(temp);
+                    reflink::reflink(filename, &temp).map_err(|e| {
+                        format!("Can't reflink {:?} to {:?}: {}", filename, new_name, e)
+                    })?;
+                    if let Err(e) = std::fs::rename(&temp, &new_name) {
+                        std::fs::remove_file(&temp).ok();
+                        return Err(e.into());
                     }
                 }
-                NamePart::Delimiter(d) => Ok(d.to_string()),
-                NamePart::String(s) => Ok(s.to_string()),
-                // NamePart::UnParsed(_) => panic!("UnParsed shouldn't exist in this stage"),
+                Reflink::Never => {
+                    std::fs::copy(filename, new_name)?;
+                }
             }
-        })
-        .collect::<Result<Vec<String>, Box<dyn Error>>>()?;
-    Ok(vars)
+        }
+        if action == Action::Move {
+            std::fs::remove_file(filename)?;
+        }
+    }
+    Ok(())
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let args = Cli::parse();
-    let hist_file = ProjectDirs::from(
-        "org",       /*qualifier*/
-        "ZeroSofts", /*organization*/
-        "nameit",    /*application*/
-    )
-    .unwrap()
-    .data_dir()
-    .join("histories.json");
-    let mut hist = read_history(&hist_file)?;
+/// Time to set for the created directories
+#[derive(Clone, Copy)]
+enum DirTime {
+    /// Latest time of the files in the directory
+    Files,
+    At(filetime::FileTime),
+}
 
-    if args.edit {
-        choose("Formats", &mut hist.formats, true, args.choices)?;
-        let new_vars: HashSet<&str> = hist
-            .formats
-            .iter()
-            .map(|s| {
-                let tmpl = NameTemplate::from(s.as_str());
-                tmpl.parts.into_iter().filter_map(|t| match t {
-                    NamePart::Variable(v) => Some(v),
-                    _ => None,
-                })
-            })
-            .flatten()
-            .collect();
-        let mut new_values = HashMap::<String, Vec<String>>::new();
-        for (k, v) in hist.values {
-            if !new_vars.contains(k.as_str()) {
-                println!("{} {}", k, "variable doesn't appear in any formats".red());
-            }
-            let mut v = v;
-            choose(&k, &mut v, true, args.choices)?;
-            if v.len() == 0 {
-                continue;
-            }
-            new_values.insert(k.to_string(), v.to_vec());
-        }
-        hist.variables = new_values.keys().map(|s| s.to_string()).collect();
-        hist.values = new_values;
-        save_history(&hist_file, &hist)?;
-        return Ok(());
+fn parse_dir_time(s: &str) -> Result<DirTime, String> {
+    if s == "files" {
+        return Ok(DirTime::Files);
     }
+    let naive = chrono::NaiveDateTime::parse_from_str(s, "%F %T")
+        .or_else(|_| NaiveDate::parse_from_str(s, "%F").map(|d| d.and_time(Default::default())))
+        .map_err(|e| format!("expected `files` or a date like 2024-01-31: {}", e))?;
+    let time = Local
+        .from_local_datetime(&naive)
+        .single()
+        .ok_or("ambiguous local time")?;
+    Ok(DirTime::At(filetime::FileTime::from_unix_time(
+        time.timestamp(),
+        0,
+    )))
+}
 
-    if args.paths.len() == 0 {
+/// Sets the modification times of the directories created in the run
+///
+/// This is done at the end as placing the files in the directories
+/// changes their times.
+fn set_dir_times(
+    dirs: &[PathBuf],
+    dir_time: DirTime,
+    files: &[&Path],
+) -> Result<(), Box<dyn Error>> {
+    for dir in dirs {
+        let time = match dir_time {
+            DirTime::At(t) => Some(t),
+            DirTime::Files => files
+                .iter()
+                .filter(|f| f.parent() == Some(dir.as_path()))
+                .filter_map(|f| f.symlink_metadata().ok())
+                .map(|m| filetime::FileTime::from_last_modification_time(&m))
+                .max(),
+        };
+        if let Some(t) = time {
+            filetime::set_file_mtime(dir, t)?;
+        }
+    }
+    Ok(())
+}
+
+/// Sets the modification times one second apart in the given order
+fn touch_in_order(files: &[&Path]) -> Result<(), Box<dyn Error>> {
+    let mut times = Vec::with_capacity(files.len());
+    for f in files {
+        times.push(filetime::FileTime::from_last_modification_time(
+            &f.symlink_metadata()?,
+        ));
+    }
+    let start = match times.iter().min() {
+        Some(t) => t.unix_seconds(),
+        None => return Ok(()),
+    };
+    for (i, f) in files.iter().enumerate() {
+        let time = filetime::FileTime::from_unix_time(start + i as i64, 0);
+        filetime::set_symlink_file_times(f, time, time)?;
+    }
+    println!(
+        "{}: modification times set for {} file(s)",
+        "Touch".green().bold(),
+        files.len()
+    );
+    Ok(())
+}
+
+/// Operation recorded in the journal after it's done
+#[derive(Serialize, Deserialize)]
+struct JournalEntry {
+    /// Start time of the run the operation was done in
+    batch: String,
+    time: String,
+    source: PathBuf,
+    destination: PathBuf,
+    action: Action,
+    /// Size of the destination after the operation
+    #[serde(default)]
+    bytes: Option<u64>,
+    /// Modification time of the destination in seconds since the unix epoch
+    #[serde(default)]
+    modified: Option<u64>,
+    /// An existing file at the destination was replaced
+    #[serde(default)]
+    replaced: bool,
+}
+
+/// Journal of the operations, one JSON entry per line
+struct Journal {
+    path: PathBuf,
+    batch: String,
+}
+
+impl Journal {
+    /// Journal for a new batch
+    ///
+    /// The batch id has the nanoseconds and the process id so that the
+    /// runs started in the same second are still separate batches.
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            batch: format!(
+                "{}-{}",
+                Local::now().format("%Y%m%d-%H%M%S%.9f"),
+                std::process::id()
+            ),
+        }
+    }
+
+    /// Adds the operation at the end of the journal
+    ///
+    /// The size and time of the destination are saved to check it hasn't
+    /// changed before undoing the operation.
+    fn record(
+        &self,
+        source: &Path,
+        destination: &Path,
+        action: Action,
+        replaced: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(par) = self.path.parent() {
+            std::fs::create_dir_all(par)?;
+        }
+        let (bytes, modified) = file_stamp(destination)?;
+        let entry = JournalEntry {
+            batch: self.batch.clone(),
+            time: Local::now().format("%F %T").to_string(),
+            source: std::path::absolute(source)?,
+            destination: std::path::absolute(destination)?,
+            action,
+            bytes: Some(bytes),
+            modified: Some(modified),
+            replaced,
+        };
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        Ok(())
+    }
+
+    /// Replaces the journal with the given operations
+    fn save(&self, entries: &[JournalEntry]) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(&self.path)?);
+        for entry in entries {
+            writeln!(writer, "{}", serde_json::to_string(entry)?)?;
+        }
+        Ok(())
+    }
+
+    /// All the recorded operations, oldest first
+    fn entries(&self) -> Result<Vec<JournalEntry>, Box<dyn Error>> {
+        let file = match File::open(&self.path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        BufReader::new(file)
+            .lines()
+            .filter(|l| l.as_ref().map_or(true, |l| !l.trim().is_empty()))
+            .map(|l| Ok(serde_json::from_str(&l?)?))
+            .collect()
+    }
+}
+
+/// Prints the last `n` operations from the journal as a table
+fn print_recent(journal: &Journal, n: usize) -> Result<(), Box<dyn Error>> {
+    let entries = journal.entries()?;
+    let recent = &entries[entries.len().saturating_sub(n)..];
+    if recent.is_empty() {
+        println!("{}", "No operations in the journal".yellow());
+        return Ok(());
+    }
+    let sources: Vec<String> = recent
+        .iter()
+        .map(|e| e.source.to_string_lossy().to_string())
+        .collect();
+    let width = sources.iter().map(|s| s.chars().count()).max().unwrap_or(0);
+    let batch_width = recent.iter().map(|e| e.batch.len()).max().unwrap_or(0);
+    println!(
+        "{:<batch_width$}  {:<19}  {:<6}  {:<width$}  {}",
+        "Batch".bold(),
+        "Time".bold(),
+        "Action".bold(),
+        "Source".bold(),
+        "Destination".bold(),
+    );
+    for (e, src) in recent.iter().zip(sources) {
+        println!(
+            "{:<batch_width$}  {:<19}  {:<6}  {:<width$}  {}",
+            e.batch,
+            e.time,
+            e.action.as_str().green(),
+            src,
+            e.destination.to_string_lossy()
+        );
+    }
+    Ok(())
+}
+
+/// Reverses the operations of a batch from the journal
+///
+/// The latest batch is used if none is given. Everything is checked
+/// first, and the undone operations are removed from the journal.
+fn undo_batch(
+    journal: &Journal,
+    batch: Option<&str>,
+    test: bool,
+    reflink: Reflink,
+) -> Result<(), Box<dyn Error>> {
+    let mut entries = journal.entries()?;
+    let batch = match batch {
+        Some(b) => b.to_string(),
+        None => entries
+            .last()
+            .ok_or("No operations in the journal")?
+            .batch
+            .clone(),
+    };
+    let ops: Vec<&JournalEntry> = entries.iter().filter(|e| e.batch == batch).collect();
+    if ops.is_empty() {
+        return Err(format!("No batch {:?} in the journal", batch).into());
+    }
+    // the files that kept their name have nothing to undo
+    let ops: Vec<&JournalEntry> = ops
+        .into_iter()
+        .filter(|op| op.source != op.destination)
+        .collect();
+    let mut problems = Vec::new();
+    // checked in the order they are undone, the paths freed or put
+    // back by the earlier ones are tracked here
+    let mut present = HashMap::<&Path, bool>::new();
+    for op in ops.iter().rev() {
+        let (source, destination) = (op.source.as_path(), op.destination.as_path());
+        let restored = present.get(destination).copied();
+        let stamp = file_stamp(destination).ok();
+        if op.replaced {
+            problems.push(format!(
+                "{:?}: replaced an existing file that can't be restored",
+                destination
+            ));
+        } else if restored == Some(false) || restored.is_none() && stamp.is_none() {
+            problems.push(format!("{:?}: missing", destination));
+        } else if restored.is_none()
+            && op.bytes.is_some()
+            && stamp.map(|(b, m)| (Some(b), Some(m))) != Some((op.bytes, op.modified))
+        {
+            problems.push(format!("{:?}: changed after the batch", destination));
+        } else if op.action != Action::Copy
+            && present
+                .get(source)
+                .copied()
+                .unwrap_or_else(|| source.symlink_metadata().is_ok())
+        {
+            problems.push(format!("{:?}: already exists", source));
+        }
+        present.insert(destination, false);
+        if op.action != Action::Copy {
+            present.insert(source, true);
+        }
+    }
+    if !problems.is_empty() {
+        eprintln!("{}:", "Batch can't be undone".red().bold());
+        for p in problems {
+            eprintln!("  {}", p);
+        }
+        return Err("Files changed after the batch, nothing was done".into());
+    }
+    // later operations are undone first
+    for op in ops.iter().rev() {
+        if op.action == Action::Copy {
+            println!("{}: {:?}", "Remove".green().bold(), op.destination);
+            if !test {
+                std::fs::remove_file(&op.destination)?;
+            }
+        } else {
+            println!(
+                "{}: {:?} -> {:?}",
+                op.action.as_str().green().bold(),
+                op.destination,
+                op.source
+            );
+            if !test {
+                if let Some(dir) = op.source.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                apply_operation(&op.destination, &op.source, op.action, true, reflink)?;
+            }
+        }
+    }
+    if test {
+        return Ok(());
+    }
+    let count = ops.len();
+    entries.retain(|e| e.batch != batch);
+    journal.save(&entries)?;
+    println!(
+        "{}: {} operation(s) from batch {}",
+        "Undone".green().bold(),
+        count,
+        batch
+    );
+    Ok(())
+}
+
+/// Applies the operations saved in the plan file
+///
+/// All the operations are checked first in order, the paths freed or
+/// taken by the earlier operations are tracked. Nothing is done if any
+/// of the sources have changed, and the existing destinations are
+/// handled with the conflict policy.
+fn apply_plan(
+    path: &Path,
+    on_conflict: OnConflict,
+    keep_symlinks: bool,
+    reflink: Reflink,
+    journal: &Journal,
+) -> Result<(), Box<dyn Error>> {
+    let plan: Vec<PlannedOperation> = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    let plan = check_plan(&plan, |dest| match on_conflict {
+        OnConflict::Replace => Ok(true),
+        OnConflict::Skip => Ok(false),
+        OnConflict::Ask => confirm_replace(dest),
+    })?;
+    for op in plan {
+        println!(
+            "{}: {:?} -> {:?}",
+            op.action.as_str().green().bold(),
+            op.source,
+            op.destination
+        );
+        // for the subdirectories from --group-by
+        if let Some(dir) = op
+            .destination
+            .parent()
+            .filter(|d| !d.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(dir)?;
+        }
+        let replaced = op.source != op.destination && op.destination.symlink_metadata().is_ok();
+        apply_operation(
+            &op.source,
+            &op.destination,
+            op.action,
+            keep_symlinks,
+            reflink,
+        )?;
+        journal.record(&op.source, &op.destination, op.action, replaced)?;
+    }
+    Ok(())
+}
+
+/// Operations of the plan that can be done, in order
+///
+/// The `replace` function decides if an existing destination can be
+/// replaced, the operation is skipped if not.
+fn check_plan(
+    plan: &[PlannedOperation],
+    mut replace: impl FnMut(&Path) -> Result<bool, Box<dyn Error>>,
+) -> Result<Vec<&PlannedOperation>, Box<dyn Error>> {
+    let mut problems = Vec::new();
+    let mut checked = Vec::with_capacity(plan.len());
+    // paths freed (false) or taken (true) by the earlier operations
+    let mut present = HashMap::<&Path, bool>::new();
+    for op in plan {
+        let (source, destination) = (op.source.as_path(), op.destination.as_path());
+        let changed = || {
+            file_stamp(source).ok().is_none_or(|(b, m)| {
+                op.bytes.is_some() && (Some(b), Some(m)) != (op.bytes, op.modified)
+            })
+        };
+        if present.get(source).copied().map_or_else(changed, |p| !p) {
+            problems.push(format!("{:?}: missing or changed", source));
+            continue;
+        }
+        let exists = present
+            .get(destination)
+            .copied()
+            .unwrap_or_else(|| destination.symlink_metadata().is_ok());
+        if source != destination && exists && !replace(destination)? {
+            println!(
+                "{}: {:?} ({:?} already exists)",
+                "Skip".yellow().bold(),
+                source,
+                destination
+            );
+            continue;
+        }
+        present.insert(destination, true);
+        if op.action != Action::Copy && source != destination {
+            present.insert(source, false);
+        }
+        checked.push(op);
+    }
+    if !problems.is_empty() {
+        eprintln!("{}:", "Plan can't be applied".red().bold());
+        for p in problems {
+            eprintln!("  {}", p);
+        }
+        return Err("Plan doesn't match the files, nothing was done".into());
+    }
+    Ok(checked)
+}
+
+/// Time spent waiting for the inputs in nanoseconds, for `--timings`
+static PROMPT_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Reads a line of input, the time waiting for it is counted as prompting
+fn read_input(buf: &mut String) -> std::io::Result<usize> {
+    let started = Instant::now();
+    let read = std::io::stdin().read_line(buf);
+    PROMPT_NANOS.fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    read
+}
+
+/// Asks if the existing file can be replaced
+fn confirm_replace(path: &Path) -> Result<bool, Box<dyn Error>> {
+    print!(
+        "{}: {:?} already exists, replace <y/N>? ",
+        "Warning".on_yellow().bold(),
+        path
+    );
+    std::io::stdout().flush()?;
+    let mut buf = String::new();
+    read_input(&mut buf)?;
+    Ok(buf.trim().to_lowercase() == "y")
+}
+
+/// Checks if two files have the same contents
+fn same_content(a: &Path, b: &Path) -> Result<bool, Box<dyn Error>> {
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+    let mut a = BufReader::new(File::open(a)?);
+    let mut b = BufReader::new(File::open(b)?);
+    loop {
+        let buf_a = a.fill_buf()?;
+        let buf_b = b.fill_buf()?;
+        let len = buf_a.len().min(buf_b.len());
+        if len == 0 {
+            return Ok(buf_a.is_empty() && buf_b.is_empty());
+        }
+        if buf_a[..len] != buf_b[..len] {
+            return Ok(false);
+        }
+        a.consume(len);
+        b.consume(len);
+    }
+}
+
+/// Files inside the directories (recursively), other paths as they are
+fn expand_dirs(
+    paths: &[PathBuf],
+    meta_ext: &str,
+    skips: &mut Skips,
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut files = Vec::new();
+    for p in paths {
+        if p.is_dir() {
+            files.extend(dir_files(p, meta_ext, skips)?);
+        } else {
+            files.push(p.clone());
+        }
+    }
+    Ok(files)
+}
+
+/// Files inside the directory and its subdirectories in sorted order
+///
+/// The symlinks to directories are not followed, and the sidecar files
+/// (`.nameit` and the metadata files) of the other files are skipped.
+fn dir_files(
+    dir: &Path,
+    meta_ext: &str,
+    skips: &mut Skips,
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<PathBuf>, _>>()?;
+    entries.sort();
+    let owners = sidecar_owners(&entries, meta_ext);
+    let mut files = Vec::new();
+    for e in &entries {
+        let meta = e.symlink_metadata()?;
+        if meta.is_dir() {
+            files.extend(dir_files(e, meta_ext, skips)?);
+        } else if meta.is_symlink() && e.is_dir() {
+            continue;
+        } else if let Some(owner) = owners.get(e.as_path()).filter(|o| o.is_file()) {
+            skips.skip("sidecar", e, &format!("(sidecar of {:?})", owner));
+        } else {
+            files.push(e.clone());
+        }
+    }
+    Ok(files)
+}
+
+/// Entries that are the sidecars of the other entries, with the entry
+/// they belong to
+fn sidecar_owners<'a>(entries: &'a [PathBuf], meta_ext: &str) -> HashMap<PathBuf, &'a Path> {
+    let names: HashSet<&Path> = entries.iter().map(|e| e.as_path()).collect();
+    let mut owners = HashMap::new();
+    for f in entries {
+        let mut sidecar = f.as_os_str().to_owned();
+        sidecar.push(".nameit");
+        let meta = f.with_extension(meta_ext);
+        for s in [PathBuf::from(sidecar), meta] {
+            if s != *f && names.contains(s.as_path()) {
+                owners.entry(s).or_insert(f.as_path());
+            }
+        }
+    }
+    owners
+}
+
+/// Table of the operations grouped by the destination directory
+fn audit_table(operations: &[(PathBuf, PathBuf)]) -> String {
+    let mut groups = BTreeMap::<&Path, Vec<(&PathBuf, &PathBuf)>>::new();
+    for (src, dest) in operations {
+        let dir = dest.parent().unwrap_or(Path::new(""));
+        groups.entry(dir).or_default().push((src, dest));
+    }
+    let mut table = String::new();
+    for (dir, ops) in groups {
+        table.push_str(&format!("{}: {:?}\n", "Directory".blue().bold(), dir));
+        let width = ops
+            .iter()
+            .map(|(s, _)| s.to_string_lossy().chars().count())
+            .max()
+            .unwrap_or(0);
+        for (src, dest) in ops {
+            table.push_str(&format!(
+                "  {:width$} -> {}\n",
+                src.to_string_lossy(),
+                dest.file_name().unwrap_or_default().to_string_lossy(),
+            ));
+        }
+    }
+    table
+}
+
+/// Script with the commands to copy/move the files
+///
+/// The destination directories that don't exist yet (e.g. from
+/// `--group-by`) are made first.
+fn shell_script(kind: ScriptKind, operations: &[(PathBuf, PathBuf)], copy: bool) -> String {
+    let dirs: BTreeSet<&Path> = operations
+        .iter()
+        .filter_map(|(_, dest)| dest.parent())
+        .filter(|d| !d.as_os_str().is_empty() && !d.is_dir())
+        .collect();
+    let mkdirs = dirs.into_iter().map(|d| {
+        let d = d.to_string_lossy();
+        match kind {
+            ScriptKind::Sh => format!("mkdir -p -- '{}'", d.replace('\'', "'\\''")),
+            ScriptKind::Ps1 => format!(
+                "New-Item -ItemType Directory -Force -Path '{}' | Out-Null",
+                d.replace('\'', "''")
+            ),
+        }
+    });
+    let lines: Vec<String> = mkdirs
+        .chain(operations.iter().map(|(src, dest)| {
+            let (src, dest) = (src.to_string_lossy(), dest.to_string_lossy());
+            match kind {
+                ScriptKind::Sh => format!(
+                    "{} -- '{}' '{}'",
+                    if copy { "cp" } else { "mv" },
+                    src.replace('\'', "'\\''"),
+                    dest.replace('\'', "'\\''"),
+                ),
+                ScriptKind::Ps1 => format!(
+                    "{} -LiteralPath '{}' -Destination '{}'",
+                    if copy { "Copy-Item" } else { "Move-Item" },
+                    src.replace('\'', "''"),
+                    dest.replace('\'', "''"),
+                ),
+            }
+        }))
+        .collect();
+    match kind {
+        ScriptKind::Sh => format!("#!/bin/sh\n{}", lines.join("\n")),
+        ScriptKind::Ps1 => lines.join("\n"),
+    }
+}
+
+/// Command line equivalent to the run with the given values
+///
+/// All the options that change the names or what is done to the files
+/// are included, the ones that only change the output (like
+/// `--relative`) are left out.
+fn emit_command(
+    args: &Cli,
+    fmt: &str,
+    delimiter: char,
+    values: &BTreeMap<String, String>,
+    destinations: &[PathBuf],
+) -> String {
+    fn name<T: ValueEnum>(v: &T) -> String {
+        v.to_possible_value()
+            .map_or(String::new(), |v| v.get_name().to_string())
+    }
+    let quote = |s: &str| format!("'{}'", s.replace('\'', "'\\''"));
+    #[cfg(feature = "script")]
+    let script_expr = args.script_expr.as_deref();
+    #[cfg(not(feature = "script"))]
+    let script_expr: Option<&str> = None;
+    let mut cmd = vec!["nameit".to_string()];
+    match script_expr {
+        Some(expr) => cmd.extend(["--script-expr".to_string(), quote(expr)]),
+        None => cmd.extend(["-f".to_string(), quote(fmt)]),
+    }
+    for (var, val) in values {
+        let setting = if val.trim() == val {
+            format!("{}={}", var, val)
+        } else {
+            format!("{}=\"{}\"", var, val)
+        };
+        cmd.extend(["--set".to_string(), quote(&setting)]);
+    }
+    let mut options = vec![
+        ("--delimiter", delimiter.to_string()),
+        ("--start", args.start.to_string()),
+        ("--step", args.step.to_string()),
+        ("--pad", args.pad.to_string()),
+        ("--base", args.base.to_string()),
+        ("--date-from", name(&args.date_from)),
+        ("--reflink", name(&args.reflink)),
+        ("--meta-ext", args.meta_ext.clone()),
+    ];
+    if !args.replace {
+        options.push(("--on-conflict", name(&args.on_conflict())));
+    }
+    if let Some(l) = &args.locale {
+        options.push(("--locale", l.clone()));
+    }
+    if let Some(var) = &args.group_by {
+        options.push(("--group-by", var.clone()));
+    }
+    if !args.only_ext.is_empty() {
+        options.push(("--only-ext", args.only_ext.join(",")));
+    }
+    if let Some(n) = args.max_files {
+        options.push(("--max-files", n.to_string()));
+    }
+    if let Some(policy) = &args.dedupe_by_hash {
+        options.push(("--dedupe-by-hash", name(policy)));
+    }
+    match args.dir_time {
+        Some(DirTime::Files) => options.push(("--dir-time", "files".to_string())),
+        Some(DirTime::At(t)) => {
+            if let Some(t) = Local.timestamp_opt(t.unix_seconds(), 0).single() {
+                options.push(("--dir-time", t.format("%F %T").to_string()));
+            }
+        }
+        None => (),
+    }
+    for (opt, val) in options {
+        cmd.extend([opt.to_string(), quote(&val)]);
+    }
+    let flags = [
+        (args.rename, "--rename"),
+        (args.r#move, "--move"),
+        (args.in_place, "--in-place"),
+        (args.replace, "--replace"),
+        (args.test, "--test"),
+        (args.per_extension_counter, "--per-extension-counter"),
+        (args.r#continue, "--continue"),
+        (args.flat_number, "--flat-number"),
+        (args.number_by_name, "--number-by-name"),
+        (args.suffix_collisions, "--suffix-collisions"),
+        (args.unique, "--unique"),
+        (args.ascii, "--ascii"),
+        (args.lower, "--lower"),
+        (args.lower_ext, "--lower-ext"),
+        (args.follow_symlinks, "--follow-symlinks"),
+        (args.no_follow_symlinks, "--no-follow-symlinks"),
+        (args.skip_identical, "--skip-identical"),
+        (args.recursive, "--recursive"),
+        (args.changed_only, "--changed-only"),
+        (args.allow_ext_change, "--allow-ext-change"),
+        (args.strict, "--strict"),
+        (args.touch_in_order, "--touch-in-order"),
+        (args.bracket_aware, "--bracket-aware"),
+    ];
+    cmd.extend(flags.iter().filter(|f| f.0).map(|f| f.1.to_string()));
+    for d in destinations {
+        cmd.extend(["-d".to_string(), quote(&d.to_string_lossy())]);
+    }
+    cmd.extend(args.paths.iter().map(|p| quote(&p.to_string_lossy())));
+    cmd.join(" ")
+}
+
+/// Number of files skipped for each reason
+#[derive(Default)]
+struct Skips(BTreeMap<&'static str, usize>);
+
+impl Skips {
+    /// Shows why the file is skipped, and counts it
+    fn skip(&mut self, code: &'static str, path: &Path, reason: &str) {
+        println!(
+            "{} [{}]: {:?} {}",
+            "Skip".yellow().bold(),
+            code,
+            path,
+            reason
+        );
+        self.add(code, 1);
+    }
+
+    fn add(&mut self, code: &'static str, count: usize) {
+        *self.0.entry(code).or_default() += count;
+    }
+
+    fn print(&self) {
+        if self.0.is_empty() {
+            return;
+        }
+        println!("{}:", "Skipped files".yellow().bold());
+        for (code, count) in &self.0 {
+            println!("  {}: {}", code, count);
+        }
+    }
+}
+
+/// Result of an operation for the JSON summary
+#[derive(Serialize)]
+struct Report {
+    source: PathBuf,
+    destination: PathBuf,
+    action: Action,
+    status: &'static str,
+    bytes: u64,
+    error: Option<String>,
+}
+
+impl Report {
+    fn new(source: &Path, destination: &Path, action: Action, status: &'static str) -> Self {
+        Self {
+            source: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+            action,
+            status,
+            bytes: source
+                .symlink_metadata()
+                .map(|m| m.len())
+                .unwrap_or_default(),
+            error: None,
+        }
+    }
+}
+
+/// Writes the JSON summary of the run
+fn write_summary(
+    path: &Path,
+    args: &Cli,
+    fmt: &str,
+    reports: &[Report],
+) -> Result<(), Box<dyn Error>> {
+    let summary = serde_json::json!({
+        "template": fmt,
+        "options": {
+            "rename": args.rename,
+            "move": args.r#move,
+            "test": args.test,
+            "replace": args.replace,
+            "in_place": args.in_place,
+            "destination": args.destination,
+        },
+        "operations": reports,
+    });
+    let writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(writer, &summary)?;
+    Ok(())
+}
+
+/// Index of the first file with same contents for each of the paths
+fn find_duplicates(paths: &[PathBuf]) -> Result<Vec<Option<usize>>, Box<dyn Error>> {
+    let mut originals = HashMap::<u64, Vec<usize>>::new();
+    let mut duplicates = Vec::with_capacity(paths.len());
+    for (i, p) in paths.iter().enumerate() {
+        let candidates = originals.entry(content_hash(p)?).or_default();
+        let mut dup = None;
+        for &j in candidates.iter() {
+            // hash can collide, so confirm with the contents
+            if same_content(&paths[j], p)? {
+                dup = Some(j);
+                break;
+            }
+        }
+        if dup.is_none() {
+            candidates.push(i);
+        }
+        duplicates.push(dup);
+    }
+    Ok(duplicates)
+}
+
+/// Compares two strings treating the runs of digits as numbers
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    fn chunks(s: &str) -> Vec<(bool, &str)> {
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        let mut digit = false;
+        for (i, c) in s.char_indices() {
+            if i != start && c.is_ascii_digit() != digit {
+                chunks.push((digit, &s[start..i]));
+                start = i;
+            }
+            if i == start {
+                digit = c.is_ascii_digit();
+            }
+        }
+        if start != s.len() {
+            chunks.push((digit, &s[start..]));
+        }
+        chunks
+    }
+    for (x, y) in chunks(a).into_iter().zip(chunks(b)) {
+        let ord = match (x, y) {
+            ((true, x), (true, y)) => {
+                let (x, y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+            }
+            ((_, x), (_, y)) => x.cmp(y),
+        };
+        if ord.is_ne() {
+            return ord;
+        }
+    }
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+/// Whether the template has parameters that differ between files
+fn varies_per_file(templ: &NameTemplate, date_from: DateSource) -> bool {
+    templ.parts.iter().any(|p| match p {
+        NamePart::Parameter(p) if p.starts_with(['#', '?', '*']) => true,
+        NamePart::Capture(..) => true,
+        NamePart::Parameter(p) if p.starts_with("@meta:") || *p == "@daily" || *p == "@cid" => true,
+        NamePart::Parameter(p) if p.starts_with('%') || *p == "@q" => date_from != DateSource::Now,
+        _ => false,
+    })
+}
+
+/// Regex matching the filenames made from the template
+///
+/// The number (`#`) parameters are captured, and the variables and
+/// other parameters can match anything.
+fn number_pattern(templ: &NameTemplate, base: u32) -> Result<Regex, Box<dyn Error>> {
+    let digits = if base == 10 {
+        r"(\d+)"
+    } else {
+        "([0-9a-zA-Z]+)"
+    };
+    let mut pattern = String::from("^");
+    for p in &templ.parts {
+        match p {
+            NamePart::String(s) | NamePart::Delimiter(s) | NamePart::Value(s) => {
+                pattern.push_str(&regex::escape(s))
+            }
+            NamePart::Parameter("#o") => pattern.push_str(r"(\d+)(?:st|nd|rd|th)"),
+            NamePart::Parameter(p) if p.starts_with('#') => pattern.push_str(digits),
+            NamePart::Variable(_) | NamePart::Parameter(_) | NamePart::Capture(..) => {
+                pattern.push_str(".*?")
+            }
+        }
+    }
+    pattern.push('$');
+    Ok(Regex::new(&pattern)?)
+}
+
+/// Values of the variables in a filename made from the template
+///
+/// Only the variables used without modifiers are taken, if one is
+/// used more than once the first part is used.
+fn parse_values(
+    templ: &NameTemplate,
+    path: &Path,
+) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let mut pattern = String::from("^");
+    let mut vars = Vec::new();
+    for p in &templ.parts {
+        match p {
+            NamePart::String(s) | NamePart::Delimiter(s) | NamePart::Value(s) => {
+                pattern.push_str(&regex::escape(s))
+            }
+            NamePart::Variable(v) if split_modifier(v).1.is_none() => {
+                pattern.push_str("(.+?)");
+                vars.push(v.to_string());
+            }
+            NamePart::Parameter(p) if p.starts_with('#') => pattern.push_str(r"\d+"),
+            NamePart::Variable(_) | NamePart::Parameter(_) | NamePart::Capture(..) => {
+                pattern.push_str(".*?")
+            }
+        }
+    }
+    pattern.push('$');
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let caps = Regex::new(&pattern)?
+        .captures(&stem)
+        .ok_or(format!("{:?} doesn't match the format", path))?;
+    let mut values: Vec<(String, String)> = Vec::new();
+    for (var, cap) in vars.into_iter().zip(caps.iter().skip(1)) {
+        if let Some(c) = cap.filter(|_| values.iter().all(|(v, _)| *v != var)) {
+            values.push((var, c.as_str().to_string()));
+        }
+    }
+    Ok(values)
+}
+
+/// Number in the filename and its number of digits
+fn existing_number(re: &Regex, path: &Path, base: u32) -> Option<(usize, usize)> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let caps = re.captures(&stem)?;
+    // the last number in the format is considered the counter
+    let num = caps.iter().skip(1).flatten().last()?.as_str();
+    let n = usize::from_str_radix(num, base).ok()?;
+    Some((n, num.len()))
+}
+
+/// Continues the numbering from the existing files in the directory
+///
+/// The files matching the template are searched for the largest
+/// number, the counter starts after it and uses the same width.
+fn continue_numbering(
+    templ: &NameTemplate,
+    dir: &Path,
+    counter: &mut Counter,
+) -> Result<(), Box<dyn Error>> {
+    let re = number_pattern(templ, counter.base())?;
+    let mut last: Option<(usize, usize)> = None;
+    for entry in std::fs::read_dir(dir)? {
+        if let Some((n, width)) = existing_number(&re, &entry?.path(), counter.base()) {
+            if last.map(|(l, _)| n > l).unwrap_or(true) {
+                last = Some((n, width));
+            }
+        }
+    }
+    if let Some((n, width)) = last {
+        counter.start = n + counter.step;
+        counter.width = counter.width.max(width);
+        println!(
+            "{}: continuing after {} in {:?}",
+            "Note".yellow().bold(),
+            counter.format(n, width),
+            dir
+        );
+    }
+    Ok(())
+}
+
+/// Numbers to use for `#` parameter for each of the paths
+fn file_numbers(paths: &[PathBuf], counter: &mut Counter, by_name: bool) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..paths.len()).collect();
+    if by_name {
+        order.sort_by(|&a, &b| {
+            natural_cmp(
+                &paths[a].file_name().unwrap_or_default().to_string_lossy(),
+                &paths[b].file_name().unwrap_or_default().to_string_lossy(),
+            )
+        });
+    }
+    let mut numbers = vec![0; paths.len()];
+    for i in order {
+        numbers[i] = counter.next(&paths[i]);
+    }
+    numbers
+}
+
+/// Path relative to the current directory for display
+fn relative_path(path: &Path) -> Result<String, Box<dyn Error>> {
+    let path = std::path::absolute(path)?;
+    let cwd = std::env::current_dir()?;
+    let common = path
+        .components()
+        .zip(cwd.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let rel: PathBuf = std::iter::repeat_n(
+        std::path::Component::ParentDir,
+        cwd.components().count() - common,
+    )
+    .chain(path.components().skip(common))
+    .collect();
+    Ok(rel.to_string_lossy().to_string())
+}
+
+/// Path with the extended-length prefix if it is too long for windows
+#[cfg(windows)]
+fn long_path(path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    // MAX_PATH is 260 including the terminating null
+    const MAX_PATH: usize = 259;
+    let abs = std::path::absolute(path)?;
+    let s = abs.to_string_lossy();
+    if s.len() < MAX_PATH || s.starts_with(r"\\?\") {
+        return Ok(path.to_path_buf());
+    }
+    Ok(match s.strip_prefix(r"\\") {
+        // network paths use the UNC prefix
+        Some(unc) => PathBuf::from(format!(r"\\?\UNC\{}", unc)),
+        None => PathBuf::from(format!(r"\\?\{}", s)),
+    })
+}
+
+/// Path as it is, long paths only need special handling in windows
+#[cfg(not(windows))]
+fn long_path(path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    Ok(path.to_path_buf())
+}
+
+/// Lines of the output of a shell command
+fn command_values(cmd: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    #[cfg(windows)]
+    let output = std::process::Command::new("cmd")
+        .args(["/C", cmd])
+        .output()?;
+    #[cfg(not(windows))]
+    let output = std::process::Command::new("sh")
+        .args(["-c", cmd])
+        .output()?;
+    if !output.status.success() {
+        return Err(format!("Command {:?} failed: {}", cmd, output.status).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect())
+}
+
+/// Size in human readable units
+fn human_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+/// Free space available in the filesystem of the directory
+#[cfg(unix)]
+fn free_space(dir: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_dir: &Path) -> Option<u64> {
+    None
+}
+
+/// Prints the total size to copy and checks the free space for it
+///
+/// Returns false if there isn't enough space and the user chose to
+/// abort.
+fn check_space(
+    paths: &[PathBuf],
+    destinations: &[Option<&Path>],
+    test: bool,
+) -> Result<bool, Box<dyn Error>> {
+    let mut required = HashMap::<PathBuf, u64>::new();
+    for p in paths {
+        let size = p.metadata()?.len();
+        for d in destinations {
+            let dir = match (d, p.parent()) {
+                (Some(d), _) => d.to_path_buf(),
+                (None, Some(par)) if par != Path::new("") => par.to_path_buf(),
+                _ => PathBuf::from("."),
+            };
+            *required.entry(dir).or_insert(0) += size;
+        }
+    }
+    let total: u64 = required.values().sum();
+    println!("{}: {} to copy", "Total".blue().bold(), human_size(total));
+    let mut enough = true;
+    for (dir, size) in &required {
+        if let Some(free) = free_space(dir) {
+            if free < *size {
+                eprintln!(
+                    "{}: {:?} only has {} free, {} needed",
+                    "Warning".on_yellow().bold(),
+                    dir,
+                    human_size(free),
+                    human_size(*size)
+                );
+                enough = false;
+            }
+        }
+    }
+    if enough || test {
+        return Ok(true);
+    }
+    print!("{} <y/N>? ", "Continue anyway".on_yellow().bold());
+    std::io::stdout().flush()?;
+    let mut buf = String::new();
+    read_input(&mut buf)?;
+    Ok(buf.trim().to_lowercase() == "y")
+}
+
+/// Date of the file from the given source
+fn file_date(path: &Path, source: DateSource) -> Result<DateTime<Local>, Box<dyn Error>> {
+    match source {
+        DateSource::Now => Ok(Local::now()),
+        DateSource::Mtime => Ok(path.metadata()?.modified()?.into()),
+        DateSource::Ctime => Ok(path.metadata()?.created()?.into()),
+        DateSource::Exif => match exif_date(path) {
+            Some(d) => Ok(d),
+            None => {
+                eprintln!(
+                    "{}: no EXIF date in {:?}, using modification time",
+                    "Warning".on_yellow().bold(),
+                    path
+                );
+                file_date(path, DateSource::Mtime)
+            }
+        },
+    }
+}
+
+/// Original date time from the EXIF metadata of the file
+fn exif_date(path: &Path) -> Option<DateTime<Local>> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let meta = exif::Reader::new().read_from_container(&mut reader).ok()?;
+    let field = [Tag::DateTimeOriginal, Tag::DateTime]
+        .iter()
+        .find_map(|t| meta.get_field(*t, In::PRIMARY))?;
+    let dt = match &field.value {
+        exif::Value::Ascii(v) => exif::DateTime::from_ascii(v.first()?).ok()?,
+        _ => return None,
+    };
+    let naive = NaiveDate::from_ymd_opt(dt.year.into(), dt.month.into(), dt.day.into())?
+        .and_hms_opt(dt.hour.into(), dt.minute.into(), dt.second.into())?;
+    Local.from_local_datetime(&naive).single()
+}
+
+/// New name from the rhai expression
+#[cfg(feature = "script")]
+fn eval_script(expr: &str, file: &FileInfo, path: &Path) -> Result<String, Box<dyn Error>> {
+    let engine = rhai::Engine::new();
+    let mut scope = rhai::Scope::new();
+    scope.push("name", file.stem.to_string());
+    scope.push(
+        "ext",
+        path.extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string(),
+    );
+    scope.push("index", file.num as i64);
+    scope.push("total", file.total as i64);
+    scope.push("size", path.metadata()?.len() as i64);
+    scope.push("date", file.date.format("%F").to_string());
+    let name = engine
+        .eval_with_scope::<rhai::Dynamic>(&mut scope, expr)
+        .map_err(|e| format!("Script error: {}", e))?;
+    Ok(name.to_string())
+}
+
+/// Scripts need the `script` feature
+#[cfg(not(feature = "script"))]
+fn eval_script(_expr: &str, _file: &FileInfo, _path: &Path) -> Result<String, Box<dyn Error>> {
+    Err("nameit was built without the script feature".into())
+}
+
+/// Suggested value for a variable computed from the current file
+///
+/// The source can be a special parameter (like `*` or `?`) or a regex
+/// whose first match (or first capture group) in the old filename is
+/// used.
+fn suggest_value(src: &str, file: &FileInfo) -> Result<Option<String>, Box<dyn Error>> {
+    if src.starts_with(['%', '*', '?', '#', '@']) {
+        if !is_parameter(src) {
+            return Err(format!("Unknown parameter {:?} for the suggestion", src).into());
+        }
+        return Ok(render_parameter(src, file).ok());
+    }
+    let re = Regex::new(src)?;
+    Ok(re.captures(file.stem).map(|c| {
+        c.get(1)
+            .unwrap_or_else(|| c.get(0).unwrap())
+            .as_str()
+            .to_string()
+    }))
+}
+
+/// Shows the reason a part of the name is empty, and leaves it empty
+fn empty_part(warning: String) -> String {
+    eprintln!("{}: {}", "Warning".on_yellow().bold(), warning);
+    String::new()
+}
+
+/// Renders a sample filename using placeholders for the variables
+///
+/// The `{}` in the placeholder is replaced by the variable name, the
+/// parts taken from the old filename are also shown as placeholders
+/// unless a sample filename is given.
+fn preview_filename(
+    templ: &NameTemplate,
+    placeholder: &str,
+    stem: Option<&str>,
+    num: usize,
+    total: usize,
+    counter: &Counter,
+    locale: Locale,
+) -> String {
+    let file = FileInfo {
+        stem: stem.unwrap_or_default(),
+        num,
+        counter,
+        total,
+        mode: None,
+        split_brackets: false,
+        delimiter: templ.delimiter,
+        meta: None,
+        cid: None,
+        date: Local::now(),
+        locale,
+    };
+    templ
+        .parts
+        .iter()
+        .map(|p| match p {
+            NamePart::Variable(v) => placeholder.replace("{}", v),
+            // the mode, content id and metadata are from the actual file, and the sections
+            // depend on the other files
+            NamePart::Parameter(p)
+                if stem.is_none() && p.starts_with(['?', '*'])
+                    || *p == "@mode"
+                    || *p == "@cid"
+                    || p.starts_with("@meta:")
+                    || p.starts_with("#when:")
+                    || *p == "#dir" =>
+            {
+                placeholder.replace("{}", p)
+            }
+            NamePart::Parameter(p) => render_parameter(p, &file).unwrap_or_else(empty_part),
+            NamePart::Capture(spec, ..) if stem.is_none() => placeholder.replace("{}", spec),
+            NamePart::Capture(_, re, group) => {
+                render_capture(re, *group, &file).unwrap_or_else(empty_part)
+            }
+            NamePart::Delimiter(d) => d.to_string(),
+            NamePart::String(s) | NamePart::Value(s) => s.to_string(),
+        })
+        .collect()
+}
+
+/// Value of the variable for the file, from the rendered name if it
+/// was used there without modifiers, the answers for `--ask-once`, or
+/// else asked for
+fn variable_value(
+    var: &str,
+    file_values: &HashMap<String, String>,
+    answers: Option<&mut HashMap<String, String>>,
+    file: &FileInfo,
+    hist: &mut History,
+    opts: &RenderOptions,
+) -> Result<String, Box<dyn Error>> {
+    let var = hist.alias_target(var).to_string();
+    let cached = file_values
+        .get(&var)
+        .or_else(|| answers.as_ref().and_then(|a| a.get(&var)));
+    let val = match cached {
+        Some(v) => v.clone(),
+        None => resolve_variable(&var, file, hist, opts)?,
+    };
+    if let Some(a) = answers {
+        a.insert(var, val.clone());
+    }
+    Ok(val)
+}
+
+/// Name with the `-copyN` suffix for the duplicates
+fn copy_name(name: String, copy: Option<usize>) -> String {
+    match copy {
+        Some(n) => format!("{}-copy{}", name, n),
+        None => name,
+    }
+}
+
+/// Adds the values of the variables for `#when:` and `--group-by`, as
+/// they can be outside the name
+fn add_extra_values(
+    templ: &NameTemplate,
+    group_by: Option<&str>,
+    values: &mut HashMap<String, String>,
+    mut answers: Option<&mut HashMap<String, String>>,
+    file: &FileInfo,
+    hist: &mut History,
+    opts: &RenderOptions,
+) -> Result<(), Box<dyn Error>> {
+    let extra_vars = templ
+        .parts
+        .iter()
+        .filter_map(|p| match p {
+            NamePart::Parameter(p) => p.strip_prefix("#when:"),
+            _ => None,
+        })
+        .chain(group_by);
+    for var in extra_vars {
+        if !values.contains_key(var) {
+            let val = variable_value(var, values, answers.as_deref_mut(), file, hist, opts)?;
+            values.insert(var.to_string(), val);
+        }
+    }
+    Ok(())
+}
+
+/// Gets the value for the variable from the user or the history
+fn resolve_variable(
+    v: &str,
+    file: &FileInfo,
+    hist: &mut History,
+    opts: &RenderOptions,
+) -> Result<String, Box<dyn Error>> {
+    if let Some(val) = opts.values.get(v) {
+        return Ok(val.clone());
+    }
+    if opts.no_prompt_new && !hist.values.contains_key(v) {
+        return Err(format!(
+            "Variable {:?} is not in the history, use --set to give its value",
+            v
+        )
+        .into());
+    }
+    if !(opts.interactive || opts.last && hist.values.contains_key(v)) {
+        return Err(format!("Variable {:?} needs a value from --set", v).into());
+    }
+    let (last, max_choice) = (opts.last, opts.max_choice);
+    let validator = match hist.validators.get(v) {
+        Some(re) => Some(Regex::new(re)?),
+        None => None,
+    };
+    let suggestion = match hist.suggestions.get(v) {
+        Some(src) => suggest_value(src, file)?,
+        None => None,
+    };
+    let label = hist.labels.get(v).map(|l| l.as_str()).unwrap_or(v);
+    if let Some(extra) = opts.extra_values.get(v) {
+        // the values from command are only saved if they are chosen
+        let mut choices = hist.values.get(v).cloned().unwrap_or_default();
+        for e in extra {
+            if !choices.contains(e) {
+                choices.push(e.clone());
+            }
+        }
+        let val = match (last, choices.first()) {
+            (true, Some(c)) => c.clone(),
+            _ => choose(
+                label,
+                &mut choices,
+                false,
+                max_choice,
+                validator.as_ref(),
+                suggestion.as_deref(),
+                opts.compact,
+            )?,
+        };
+        hist.variables.insert(v.to_string());
+        let k = hist.values.entry(v.to_string()).or_default();
+        k.retain(|c| c != &val);
+        k.insert(0, val.clone());
+        return Ok(val);
+    }
+    match hist.values.get_mut(v) {
+        Some(k) => {
+            if last {
+                Ok(k[0].clone())
+            } else {
+                choose(
+                    label,
+                    k,
+                    false,
+                    max_choice,
+                    validator.as_ref(),
+                    suggestion.as_deref(),
+                    opts.compact,
+                )
+            }
+        }
+        None => {
+            hist.variables.insert(v.to_string());
+            let mut newvec = vec![];
+            // here since the variable is not new when --last
+            // is used it won't happen, so I'll leave it be
+            // interactive. Is manual format is given from
+            // TUI, it'll need one time input.
+            let var = choose(
+                label,
+                &mut newvec,
+                false,
+                max_choice,
+                validator.as_ref(),
+                suggestion.as_deref(),
+                opts.compact,
+            );
+            hist.values.insert(v.to_string(), newvec);
+            var
+        }
+    }
+}
+
+/// Options for how the variables are resolved
+struct RenderOptions {
+    /// Choose the first option for all the variables
+    last: bool,
+    /// Number of choices to show from history
+    max_choice: usize,
+    /// Error on variables not in the history
+    no_prompt_new: bool,
+    /// Ask the user for the values not given
+    interactive: bool,
+    /// Show short lists of choices in the same line as the prompt
+    compact: bool,
+    /// Values given for the variables from CLI
+    values: HashMap<String, String>,
+    /// Additional choices for the variables from commands
+    extra_values: HashMap<String, Vec<String>>,
+}
+
+/// Rendered parts of the template and the values of the variables
+///
+/// The values are given by the variable they are saved for, so the
+/// aliases use the name of their variable.
+fn render_filename(
+    file: &FileInfo,
+    hist: &mut History,
+    templ: NameTemplate,
+    opts: &RenderOptions,
+    mut answers: Option<&mut HashMap<String, String>>,
+) -> Result<(Vec<String>, HashMap<String, String>), Box<dyn Error>> {
+    // variables are resolved in order, and the same variable used
+    // again (e.g. with a modifier) reuses the value
+    let mut resolved = HashMap::<String, String>::new();
+    let vars: Vec<String> = templ
+        .parts
+        .into_iter()
+        .map(|p| {
+            match p {
+                NamePart::Variable(v) => {
+                    let (name, modifier) = split_modifier(v);
+                    let name = hist.alias_target(name).to_string();
+                    let cached = resolved
+                        .get(&name)
+                        .or_else(|| answers.as_ref().and_then(|a| a.get(&name)));
+                    let val = match cached {
+                        Some(val) => val.clone(),
+                        None => resolve_variable(&name, file, hist, opts)?,
+                    };
+                    if let Some(a) = answers.as_mut() {
+                        a.insert(name.to_string(), val.clone());
+                    }
+                    let rendered = apply_modifier(&val, modifier);
+                    resolved.insert(name, val);
+                    rendered
+                }
+                NamePart::Parameter(p) => Ok(render_parameter(p, file).unwrap_or_else(empty_part)),
+                NamePart::Capture(_, re, group) => {
+                    Ok(render_capture(&re, group, file).unwrap_or_else(empty_part))
+                }
+                NamePart::Delimiter(d) => Ok(d.to_string()),
+                NamePart::String(s) | NamePart::Value(s) => Ok(s.to_string()),
+                // NamePart::UnParsed(_) => panic!("UnParsed shouldn't exist in this stage"),
+            }
+        })
+        .collect::<Result<Vec<String>, Box<dyn Error>>>()?;
+    Ok((vars, resolved))
+}
+
+/// Renders the name for the `render` subcommand input
+fn render_json(args: &RenderArgs) -> Result<serde_json::Value, Box<dyn Error>> {
+    let input: RenderInput = serde_json::from_reader(std::io::stdin().lock())?;
+    let templ = NameTemplate::parse(input.template.as_str(), args.delimiter)?;
+    let mut planner = Planner::new(templ, input.values);
+    planner.counter = Counter::default().with_base(args.base)?;
+    planner.counter.width = args.pad;
+    planner.ascii = args.ascii;
+    planner.lower = args.lower;
+    planner.lower_ext = args.lower_ext;
+    planner.split_brackets = args.bracket_aware;
+    planner.locale = parse_locale(args.locale.as_deref());
+    let (new_name, warnings) = planner.render(&input.filename, input.index, 1)?;
+    let name = new_name
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    Ok(serde_json::json!({ "name": name, "warnings": warnings }))
+}
+
+fn main() {
+    // the errors are shown with their message, not `Debug`
+    if let Err(e) = run() {
+        eprintln!("{}: {}", "Error".red().bold(), e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let started = Instant::now();
+    let args = Cli::parse();
+    if let Some(Command::Render(render_args)) = &args.command {
+        match render_json(render_args) {
+            Ok(output) => println!("{}", output),
+            Err(e) => {
+                println!("{}", serde_json::json!({ "error": e.to_string() }));
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    let locale = args.locale();
+    let choices = args.choices();
+    if let Some(fmt) = &args.preview {
+        let templ = NameTemplate::parse(fmt.as_str(), args.delimiter)?;
+        let mut counter = args.counter();
+        let num = counter.next(Path::new(""));
+        println!(
+            "{}",
+            preview_filename(&templ, &args.placeholder, None, num, 1, &counter, locale)
+        );
+        return Ok(());
+    }
+    let hist_file = ProjectDirs::from(
+        "org",       /*qualifier*/
+        "ZeroSofts", /*organization*/
+        "nameit",    /*application*/
+    )
+    .unwrap()
+    .data_dir()
+    .join("histories.json");
+    let journal = Journal::new(hist_file.with_file_name("journal.jsonl"));
+    if let Some(plan) = &args.apply_plan {
+        return apply_plan(
+            plan,
+            args.on_conflict(),
+            args.no_follow_symlinks,
+            args.reflink,
+            &journal,
+        );
+    }
+    if let Some(samples) = &args.dry_run_samples {
+        let fmt = args.format.as_ref().unwrap();
+        let templ = NameTemplate::parse(fmt.as_str(), args.delimiter)?;
+        let samples: Vec<PathBuf> = std::fs::read_to_string(samples)?
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty())
+            .map(PathBuf::from)
+            .collect();
+        let mut counter = args.counter();
+        let numbers = file_numbers(&samples, &mut counter, args.number_by_name);
+        let width = samples
+            .iter()
+            .map(|s| s.to_string_lossy().chars().count())
+            .max()
+            .unwrap_or_default();
+        for (i, sample) in samples.iter().enumerate() {
+            let stem = sample.file_stem().unwrap_or_default().to_string_lossy();
+            let mut name = preview_filename(
+                &templ,
+                &args.placeholder,
+                Some(&stem),
+                numbers[i],
+                samples.len(),
+                &counter,
+                locale,
+            );
+            if let Some(e) = sample.extension() {
+                name = format!("{}.{}", name, e.to_string_lossy());
+            }
+            println!(
+                "{:width$}  {}",
+                sample.to_string_lossy(),
+                name.green(),
+                width = width
+            );
+        }
+        return Ok(());
+    }
+    if args.r#where {
+        println!("{}", hist_file.to_string_lossy());
+        return Ok(());
+    }
+    if args.migrate_history {
+        return migrate_history(&hist_file);
+    }
+    if let Some(n) = args.recent {
+        return print_recent(&journal, n);
+    }
+    if let Some(batch) = &args.undo {
+        let batch = Some(batch.as_str()).filter(|b| !b.is_empty());
+        return undo_batch(&journal, batch, args.test, args.reflink);
+    }
+    let mut hist = read_history(&hist_file)?;
+    if args.stats {
+        let size = match hist_file.metadata() {
+            Ok(m) => human_size(m.len()),
+            Err(_) => "not created".to_string(),
+        };
+        println!("{}: {}", "Formats".yellow().bold(), hist.formats.len());
+        if let Some(f) = &hist.default_format {
+            println!("{}: {}", "Default format".yellow().bold(), f);
+        }
+        println!("{}: {}", "Variables".blue().bold(), hist.values.len());
+        println!(
+            "{}: {}",
+            "Values".blue().bold(),
+            hist.values.values().map(|v| v.len()).sum::<usize>()
+        );
+        println!("{}: {}", "File size".green().bold(), size);
         return Ok(());
     }
 
-    let fmt_str = if let Some(f) = args.format {
-        f
-    } else {
-        if args.last {
-            hist.formats[0].clone()
+    for val in &args.validate {
+        if let (_, Some(re)) = parse_var_setting(val)? {
+            Regex::new(re)?;
+        }
+    }
+    let settings = [
+        (&args.validate, &mut hist.validators),
+        (&args.suggest, &mut hist.suggestions),
+        (&args.label, &mut hist.labels),
+        (&args.alias, &mut hist.aliases),
+    ];
+    let mut changed = false;
+    for (opts, map) in settings {
+        for opt in opts {
+            match parse_var_setting(opt)? {
+                (var, Some(val)) => map.insert(var.to_string(), val.to_string()),
+                (var, None) => map.remove(var),
+            };
+            changed = true;
+        }
+    }
+    if let Some(fmt) = &args.set_default_format {
+        hist.default_format = if fmt.is_empty() {
+            None
+        } else {
+            hist.set_delimiter(fmt, args.delimiter);
+            Some(fmt.to_string())
+        };
+        changed = true;
+    }
+    if let Some(max) = args.max_values_per_var {
+        hist.max_values = (max > 0).then_some(max);
+        changed = true;
+    }
+    if let Some([from, into]) = args.merge_variables.as_deref() {
+        let from_values = hist
+            .values
+            .remove(from)
+            .ok_or(format!("Variable {:?} is not in the history", from))?;
+        let values = hist.values.entry(into.to_string()).or_default();
+        for v in from_values {
+            if !values.contains(&v) {
+                values.push(v);
+            }
+        }
+        hist.variables.remove(from);
+        hist.variables.insert(into.to_string());
+        println!("{}: {} into {}", "Merged".green().bold(), from, into);
+        changed = true;
+    }
+    if changed {
+        save_history(&hist_file, &hist)?;
+    }
+
+    if args.edit {
+        let old_hist = hist.clone();
+        choose(
+            "Formats",
+            &mut hist.formats,
+            true,
+            choices,
+            None,
+            None,
+            args.compact_prompt,
+        )?;
+        let new_vars: HashSet<&str> = hist
+            .formats
+            .iter()
+            .flat_map(|s| {
+                // formats that can't be parsed don't have any variables
+                let delimiter = old_hist.delimiters.get(s).copied().unwrap_or('_');
+                let parts = NameTemplate::parse(s.as_str(), delimiter).map_or(vec![], |t| t.parts);
+                parts.into_iter().filter_map(|t| match t {
+                    NamePart::Variable(v) => Some(split_modifier(v).0),
+                    _ => None,
+                })
+            })
+            .collect();
+        let mut new_values = BTreeMap::<String, Vec<String>>::new();
+        for (k, v) in hist.values {
+            if !new_vars.contains(k.as_str()) {
+                println!("{} {}", k, "variable doesn't appear in any formats".red());
+            }
+            let mut v = v;
+            choose(&k, &mut v, true, choices, None, None, args.compact_prompt)?;
+            if v.is_empty() {
+                continue;
+            }
+            new_values.insert(k.to_string(), v.to_vec());
+        }
+        hist.variables = new_values.keys().map(|s| s.to_string()).collect();
+        hist.values = new_values;
+        hist.delimiters
+            .retain(|f, _| hist.formats.contains(f) || hist.default_format.as_ref() == Some(f));
+        let reordered = hist.formats != old_hist.formats || hist.values != old_hist.values;
+        if !print_new_entries("Removed history entries", &hist, &old_hist) {
+            if !reordered {
+                println!("{}", "No changes to save".yellow());
+                return Ok(());
+            }
+            println!("{}", "History entries reordered".green().bold());
+        }
+        print!("{} <y/N>? ", "Save the changes".on_yellow().bold());
+        std::io::stdout().flush()?;
+        let mut buf = String::new();
+        read_input(&mut buf)?;
+        if buf.trim().to_lowercase() == "y" {
+            save_history(&hist_file, &hist)?;
         } else {
-            choose("Format", &mut hist.formats, false, args.choices)?
+            println!("{}", "Changes discarded".red());
+        }
+        return Ok(());
+    }
+
+    if args.destination.len() > 1 && (args.rename || args.r#move) {
+        return Err("Multiple destinations only work when copying".into());
+    }
+    let mut skips = Skips::default();
+    let mut paths = if args.recursive {
+        expand_dirs(&args.paths, &args.meta_ext, &mut skips)?
+    } else {
+        args.paths.clone()
+    };
+    let missing: Vec<&PathBuf> = paths
+        .iter()
+        .filter(|p| p.symlink_metadata().map(|m| m.is_dir()).unwrap_or(true))
+        .collect();
+    if !missing.is_empty() {
+        eprintln!("{}:", "Missing files".red().bold());
+        for p in missing {
+            let reason = if p.is_dir() { " (directory)" } else { "" };
+            eprintln!("  {:?}{}", p, reason);
+        }
+        return Err("Some of the paths are not files, nothing was done".into());
+    }
+    if !args.only_ext.is_empty() {
+        let exts: Vec<String> = args
+            .only_ext
+            .iter()
+            .map(|e| e.trim_start_matches('.').to_lowercase())
+            .collect();
+        paths.retain(|p| {
+            let keep = match p.extension() {
+                Some(e) => exts.contains(&e.to_string_lossy().to_lowercase()),
+                None => false,
+            };
+            if !keep {
+                skips.skip("filtered", p, "(doesn't match the extensions)");
+            }
+            keep
+        });
+    }
+    if let Some(max) = args.max_files {
+        if paths.len() > max {
+            println!(
+                "{}: batch capped at {} file(s), {} remaining file(s) ignored",
+                "Note".yellow().bold(),
+                max,
+                paths.len() - max
+            );
+            skips.add("capped", paths.len() - max);
+            paths.truncate(max);
+        }
+    }
+    if args.follow_symlinks {
+        for p in paths.iter_mut() {
+            if p.is_symlink() {
+                *p = p.canonicalize()?;
+            }
         }
+    }
+    if paths.is_empty() {
+        return Ok(());
+    }
+
+    let mut render_opts = RenderOptions {
+        last: args.last,
+        max_choice: choices,
+        no_prompt_new: args.no_prompt_new,
+        interactive: !args.stdout,
+        compact: args.compact_prompt,
+        values: HashMap::new(),
+        extra_values: HashMap::new(),
     };
-    let templ = NameTemplate::from(fmt_str.as_str());
-    println!("{}: {}", "Template".yellow().bold(), templ.to_string());
-
-    for (i, filename) in args.paths.iter().enumerate() {
-        println!("{}: {:?}", "File".blue().bold(), filename);
-        let ext = filename.extension();
-        let fname_parts: Vec<String> = render_filename(
-            &filename.file_stem().unwrap_or_default().to_string_lossy(),
+    for opt in &args.values_cmd {
+        if !args.allow_exec {
+            return Err("--values-cmd needs --allow-exec to run commands".into());
+        }
+        let (var, cmd) = parse_var_setting(opt)?;
+        let cmd = cmd.ok_or_else(|| format!("No command given for {:?}", var))?;
+        render_opts
+            .extra_values
+            .insert(hist.alias_target(var).to_string(), command_values(cmd)?);
+    }
+    for opt in &args.set {
+        let (var, val) = parse_var_setting(opt)?;
+        render_opts.values.insert(
+            hist.alias_target(var).to_string(),
+            val.unwrap_or_default().to_string(),
+        );
+    }
+    if args.stdout {
+        if paths.len() != 1 {
+            return Err("--stdout needs exactly one path".into());
+        }
+        let (fmt, delimiter) = match (args.cli_format(paths.len()), args.last) {
+            (Some(f), _) => (f, args.delimiter),
+            (None, true) => {
+                let f = hist.last_format().cloned().ok_or("No formats in history")?;
+                let d = hist.format_delimiter(&f, args.delimiter)?;
+                (f, d)
+            }
+            (None, false) => return Err("--stdout needs --format or --last".into()),
+        };
+        // same steps as the batch below, without asking or saving anything
+        let filename = &paths[0];
+        let planner = args.planner(
+            NameTemplate::parse(fmt.as_str(), delimiter)?,
+            render_opts.values.clone(),
+        );
+        let sidecar_fmt = planner.sidecar_format(filename)?;
+        let templ = match &sidecar_fmt {
+            Some(f) => NameTemplate::parse(f.as_str(), planner.template.delimiter)?,
+            None => planner.template.clone(),
+        };
+        let mut counter = args.counter();
+        let file_info = FileInfo {
+            stem: &filename.file_stem().unwrap_or_default().to_string_lossy(),
+            num: counter.next(filename),
+            counter: &counter,
+            mode: file_mode(&templ, filename)?,
+            split_brackets: args.bracket_aware,
+            delimiter: templ.delimiter,
+            meta: if templ.uses_meta() {
+                read_meta(filename, &args.meta_ext)?
+            } else {
+                None
+            },
+            cid: content_id(&templ, filename)?,
+            total: 1,
+            date: file_date(filename, args.date_from)?,
+            locale,
+        };
+        let (mut parts, mut values) =
+            render_filename(&file_info, &mut hist, templ.clone(), &render_opts, None)?;
+        if let Some((k, v)) = separator_parts(&templ, &parts).first() {
+            return Err(format!("value {:?} for {:?} has a path separator", parts[*k], v).into());
+        }
+        add_extra_values(
+            &templ,
+            args.group_by.as_deref(),
+            &mut values,
+            None,
+            &file_info,
             &mut hist,
-            templ.clone(),
-            i + 1,
-            args.last,
-            args.choices,
+            &render_opts,
         )?;
-        save_history(&hist_file, &hist)?;
+        let destination = args
+            .destination
+            .first()
+            .filter(|_| !args.in_place)
+            .map(|d| d.as_path());
+        let group = planner.group_dir(filename, &values, destination)?;
+        let dir = group
+            .as_deref()
+            .or(destination)
+            .or(filename.parent())
+            .unwrap_or(Path::new(""));
+        // `@daily` continues from the history, but isn't saved
+        let mut batch = Batch {
+            daily: hist.daily.clone(),
+            ..Default::default()
+        };
+        batch.fill(&templ, &mut parts, &values, dir)?;
+        let fname = planner.file_name(filename, &templ, &parts)?;
+        let (new_name, _) =
+            planner.target(&batch, filename, &fname, group.as_deref().or(destination));
+        println!("{}", std::path::absolute(new_name)?.to_string_lossy());
+        return Ok(());
+    }
 
-        let fname_repr: String = NameTemplate {
-            parts: fname_parts
-                .iter()
-                .zip(&templ.parts)
-                .map(|(p, t)| match t {
-                    NamePart::String(_) => NamePart::String(p),
-                    NamePart::Delimiter(_) => NamePart::Delimiter(p),
-                    NamePart::Variable(_) => NamePart::Variable(p),
-                    NamePart::Parameter(_) => NamePart::Parameter(p),
-                })
-                .collect(),
-        }
-        .to_string()
-        .replace(" ", "-");
-        let fname = fname_parts.join("").replace(" ", "-");
-        let mut new_name = match ext {
-            None => filename.with_file_name(fname),
-            Some(e) => filename.with_file_name(format!(
-                // .with_extension() thing didn't work as it removes any
-                // part of the name after first '.' in filename
-                "{}.{}",
-                fname,
-                e.to_string_lossy(),
-            )),
+    #[cfg(feature = "script")]
+    let script_expr = args.script_expr.as_deref();
+    #[cfg(not(feature = "script"))]
+    let script_expr: Option<&str> = None;
+
+    let old_hist = hist.clone();
+    let (fmt_str, delimiter) = if let Some(expr) = script_expr {
+        println!("{}: {}", "Script".yellow().bold(), expr);
+        // the whole name comes from the script
+        ("?".to_string(), args.delimiter)
+    } else if let Some(f) = args.cli_format(paths.len()) {
+        (f, args.delimiter)
+    } else {
+        let f = if args.last {
+            hist.last_format().cloned().ok_or("No formats in history")?
+        } else {
+            let saved = hist.formats.clone();
+            let f = choose(
+                "Format",
+                &mut hist.formats,
+                false,
+                choices,
+                None,
+                None,
+                args.compact_prompt,
+            )?;
+            if !saved.contains(&f) {
+                hist.set_delimiter(&f, args.delimiter);
+            }
+            f
         };
-        if let Some(d) = &args.destination {
-            // if destination is given discard the parent directory information
-            new_name = d.join(new_name.file_name().unwrap());
+        let d = hist.format_delimiter(&f, args.delimiter)?;
+        (f, d)
+    };
+    let templ = NameTemplate::parse(fmt_str.as_str(), delimiter)?;
+    if script_expr.is_none() {
+        println!("{}: {}", "Template".yellow().bold(), templ);
+    }
+    if let Some(like) = &args.like {
+        for (var, val) in parse_values(&templ, like)? {
+            let var = hist.alias_target(&var).to_string();
+            if let Entry::Vacant(e) = render_opts.values.entry(var) {
+                println!("{}: {}={:?}", "Like".yellow().bold(), e.key(), val);
+                e.insert(val);
+            }
         }
+    }
+    if args.confirm_format && script_expr.is_none() && !args.last && std::io::stdin().is_terminal()
+    {
+        let counter = args.counter();
+        let stem = paths[0].file_stem().map(|s| s.to_string_lossy());
         println!(
-            "{}: {:?} -> {}",
-            (match (args.rename, args.r#move) {
-                (true, false) => "Rename",
-                (false, true) => "Move",
-                (false, false) => "Copy",
-                _ => panic!("Forgot a case for CLI arguments related to move"),
-            })
-            .green()
-            .bold(),
-            filename,
-            // this is a HACK to just replace the rendered name, need
-            // to properly set it up somehow later.
-            format!("{:?}", new_name).replace(
-                &*new_name
-                    .with_extension("")
-                    .file_name()
-                    .unwrap()
-                    .to_string_lossy(),
-                &fname_repr
+            "{}: {}",
+            "Sample".yellow().bold(),
+            preview_filename(
+                &templ,
+                &args.placeholder,
+                stem.as_deref(),
+                counter.start,
+                paths.len(),
+                &counter,
+                locale
             )
         );
-        if args.test {
-            continue;
+        print!("{} <y/N>? ", "Use this format".on_yellow().bold());
+        std::io::stdout().flush()?;
+        let mut buf = String::new();
+        read_input(&mut buf)?;
+        if buf.trim().to_lowercase() != "y" {
+            println!("{}", "Format not confirmed, nothing done".red());
+            return Ok(());
+        }
+    }
+    if paths.len() > 1 && !varies_per_file(&templ, args.date_from) {
+        let msg = "the format has nothing that changes between the files, add `#` for a number";
+        if args.strict {
+            return Err(msg.into());
+        }
+        println!("{}: {}", "Warning".on_yellow().bold(), msg);
+    }
+
+    let mut flat_width = 0;
+    if args.flat_number {
+        let base = args.base;
+        let re = number_pattern(&templ, base)?;
+        let mut numbered: Vec<(Option<(usize, usize)>, PathBuf)> = paths
+            .into_iter()
+            .map(|p| (existing_number(&re, &p, base), p))
+            .collect();
+        // files without a number go after the numbered ones
+        numbered.sort_by_key(|(n, _)| n.map_or((1, 0), |(n, _)| (0, n)));
+        flat_width = numbered
+            .iter()
+            .filter_map(|(n, _)| *n)
+            .map(|n| n.1)
+            .max()
+            .unwrap_or(0);
+        paths = numbered.into_iter().map(|(_, p)| p).collect();
+    }
+
+    let mut duplicates = match args.dedupe_by_hash {
+        Some(_) => find_duplicates(&paths)?,
+        None => vec![None; paths.len()],
+    };
+    if let Some(DedupePolicy::Skip) = args.dedupe_by_hash {
+        for (p, dup) in paths.iter().zip(&duplicates) {
+            if let Some(j) = dup {
+                skips.skip("duplicate", p, &format!("(duplicate of {:?})", paths[*j]));
+            }
+        }
+        paths = paths
+            .into_iter()
+            .zip(duplicates)
+            .filter_map(|(p, dup)| dup.is_none().then_some(p))
+            .collect();
+        duplicates = vec![None; paths.len()];
+    }
+    let mut counter = args.counter();
+    counter.width = counter.width.max(flat_width);
+    if args.r#continue {
+        let dir = match (args.destination.first(), args.in_place, paths[0].parent()) {
+            (Some(d), false, _) => d.clone(),
+            (_, _, Some(p)) if p != Path::new("") => p.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        continue_numbering(&templ, &dir, &mut counter)?;
+    }
+    let numbers = file_numbers(&paths, &mut counter, args.number_by_name);
+    let mut rendered_names = Vec::<String>::new();
+    let mut answers = HashMap::<String, String>::new();
+    let mut operations = Vec::<(PathBuf, PathBuf)>::new();
+    let destinations: Vec<Option<&Path>> = if args.destination.is_empty() || args.in_place {
+        vec![None]
+    } else {
+        args.destination.iter().map(|d| Some(d.as_path())).collect()
+    };
+    if !args.rename && !check_space(&paths, &destinations, args.test)? {
+        return Ok(());
+    }
+    let mut suffixed = Vec::<PathBuf>::new();
+    let mut reports = Vec::<Report>::new();
+    let on_conflict = args.on_conflict();
+    let action = match (args.rename, args.r#move) {
+        (true, false) => Action::Rename,
+        (false, true) => Action::Move,
+        (false, false) => Action::Copy,
+        _ => panic!("Forgot a case for CLI arguments related to move"),
+    };
+    // the values are asked for each file, so only the options are used
+    let mut planner = args.planner(templ.clone(), render_opts.values.clone());
+    planner.action = action;
+    // the script gives the whole name, so the sidecar formats aren't used
+    planner.sidecars = script_expr.is_none();
+    // the `@daily` numbers given in test mode are not saved
+    let mut batch = Batch {
+        daily: hist.daily.clone(),
+        ..Default::default()
+    };
+    // values for --emit-command, None if they differ between files
+    let mut used_values = BTreeMap::<String, Option<String>>::new();
+    // destinations chosen with --ask-destination, for --emit-command
+    let mut asked_destinations = BTreeSet::<PathBuf>::new();
+    // answers for replacing the path separators in the values
+    let mut separator_answers = HashMap::<String, bool>::new();
+    // directories made for --group-by and --ask-destination
+    let mut created_dirs = Vec::<PathBuf>::new();
+    let mut rendered_parts = HashMap::<usize, (Vec<String>, HashMap<String, String>, usize)>::new();
+    let loop_started = Instant::now();
+    let mut execution = Duration::ZERO;
+    for (i, (filename, num)) in paths.iter().zip(numbers.iter().copied()).enumerate() {
+        if !args.changed_only {
+            println!("{}: {:?}", "File".blue().bold(), filename);
+        }
+        // duplicates use the same format as their original file
+        let sidecar_fmt = planner.sidecar_format(duplicates[i].map_or(filename, |j| &paths[j]))?;
+        let templ = match &sidecar_fmt {
+            Some(f) => {
+                let t = NameTemplate::parse(f.as_str(), planner.template.delimiter)?;
+                println!("{}: {}", "Sidecar Template".yellow().bold(), t);
+                t
+            }
+            None => templ.clone(),
+        };
+        let file_info = FileInfo {
+            stem: &filename.file_stem().unwrap_or_default().to_string_lossy(),
+            num,
+            counter: &counter,
+            mode: file_mode(&templ, filename)?,
+            split_brackets: args.bracket_aware,
+            delimiter: templ.delimiter,
+            meta: if templ.uses_meta() {
+                read_meta(filename, &args.meta_ext)?
+            } else {
+                None
+            },
+            cid: content_id(&templ, filename)?,
+            total: paths.len(),
+            date: file_date(filename, args.date_from)?,
+            locale,
+        };
+        // the duplicates get a copy suffix after the name is filled
+        let mut copy = None;
+        let (mut fname_parts, mut values) = if let Some(j) = duplicates[i] {
+            let (parts, values, copies) = rendered_parts.get_mut(&j).unwrap();
+            *copies += 1;
+            copy = Some(*copies);
+            (parts.clone(), values.clone())
+        } else {
+            let (parts, values) = if let Some(expr) = script_expr {
+                (
+                    vec![eval_script(expr, &file_info, filename)?],
+                    HashMap::new(),
+                )
+            } else {
+                let rendered = render_filename(
+                    &file_info,
+                    &mut hist,
+                    templ.clone(),
+                    &render_opts,
+                    args.ask_once.then_some(&mut answers),
+                )?;
+                save_history(&hist_file, &hist)?;
+                rendered
+            };
+            if args.dedupe_by_hash.is_some() {
+                rendered_parts.insert(i, (parts.clone(), values.clone(), 0));
+            }
+            (parts, values)
+        };
+
+        // a `/` in a value would put the file in a subdirectory
+        for (k, v) in separator_parts(&templ, &fname_parts) {
+            let p = &mut fname_parts[k];
+            let msg = format!("value {:?} for {:?} has a path separator", p, v);
+            if args.strict {
+                return Err(msg.into());
+            }
+            print!("{}: {}", "Warning".on_yellow().bold(), msg);
+            if args.test {
+                println!();
+                continue;
+            }
+            let replace = match separator_answers.get(p.as_str()) {
+                Some(r) => {
+                    println!();
+                    *r
+                }
+                None => {
+                    print!(", replace it with `-` <y/N>? ");
+                    std::io::stdout().flush()?;
+                    let mut buf = String::new();
+                    read_input(&mut buf)?;
+                    let r = buf.trim().to_lowercase() == "y";
+                    separator_answers.insert(p.clone(), r);
+                    r
+                }
+            };
+            if replace {
+                *p = p.replace(std::path::is_separator, "-");
+                // so that --emit-command gives the replaced value
+                if let (name, None) = split_modifier(v) {
+                    values.insert(hist.alias_target(name).to_string(), p.clone());
+                }
+            }
+        }
+        add_extra_values(
+            &templ,
+            args.group_by.as_deref(),
+            &mut values,
+            args.ask_once.then_some(&mut answers),
+            &file_info,
+            &mut hist,
+            &render_opts,
+        )?;
+        for (var, val) in &values {
+            let used = used_values
+                .entry(hist.alias_target(var).to_string())
+                .or_insert_with(|| Some(val.clone()));
+            if used.as_ref() != Some(val) {
+                *used = None;
+            }
+        }
+        save_history(&hist_file, &hist)?;
+        let asked_destination = if args.ask_destination {
+            let dest = choose(
+                "Destination",
+                &mut hist.destinations,
+                false,
+                choices,
+                None,
+                None,
+                args.compact_prompt,
+            )?;
+            save_history(&hist_file, &hist)?;
+            let dest = PathBuf::from(dest);
+            if !args.test && !dest.is_dir() {
+                std::fs::create_dir_all(&dest)?;
+                created_dirs.push(dest.clone());
+            }
+            Some(dest)
+        } else {
+            None
+        };
+        asked_destinations.extend(asked_destination.clone());
+        let file_destinations = match &asked_destination {
+            Some(d) => vec![Some(d.as_path())],
+            None => destinations.clone(),
+        };
+        // `#dir` is numbered in the directory for the first destination
+        let group = planner.group_dir(filename, &values, file_destinations[0])?;
+        let dir = group
+            .as_deref()
+            .or(file_destinations[0])
+            .or(filename.parent())
+            .unwrap_or(Path::new(""));
+        batch.fill(&templ, &mut fname_parts, &values, dir)?;
+        if !args.test && templ.has_parameter("@daily") {
+            hist.daily = batch.daily.clone();
+            save_history(&hist_file, &hist)?;
         }
-        if new_name.exists() {
-            if !args.replace {
+        let fname_repr: String = sanitize(
+            &NameTemplate {
+                delimiter: templ.delimiter,
+                parts: fname_parts
+                    .iter()
+                    .zip(&templ.parts)
+                    .map(|(p, t)| match t {
+                        NamePart::String(_) => NamePart::String(p),
+                        NamePart::Delimiter(_) => NamePart::Delimiter(p),
+                        NamePart::Variable(_) => NamePart::Variable(p),
+                        NamePart::Value(_) => NamePart::Value(p),
+                        NamePart::Parameter(_) | NamePart::Capture(..) => NamePart::Parameter(p),
+                    })
+                    .collect(),
+            }
+            .to_string(),
+            args.ascii,
+            args.lower,
+        );
+        let fname = copy_name(planner.file_name(filename, &templ, &fname_parts)?, copy);
+        let fname_repr = copy_name(fname_repr, copy);
+        for destination in &file_destinations {
+            let group_dir = planner.group_dir(filename, &values, *destination)?;
+            let (new_name, suffix) = planner.target(
+                &batch,
+                filename,
+                &fname,
+                group_dir.as_deref().or(*destination),
+            );
+            let mut fname_repr = fname_repr.clone();
+            if let Some(n) = suffix {
+                fname_repr = format!("{}-{}", fname_repr, n);
+                println!(
+                    "{}: name already used, suffixed with -{}",
+                    "Note".yellow().bold(),
+                    n
+                );
+                suffixed.push(filename.clone());
+            }
+            batch.names.insert(new_name.clone());
+            if args.changed_only && new_name == *filename {
+                skips.add("unchanged", 1);
+                continue;
+            }
+            let (from, to) = if args.relative {
+                (relative_path(filename)?, relative_path(&new_name)?)
+            } else {
+                (format!("{:?}", filename), format!("{:?}", new_name))
+            };
+            println!(
+                "{}: {} -> {}",
+                action.as_str().green().bold(),
+                from,
+                // this is a HACK to just replace the rendered name, need
+                // to properly set it up somehow later.
+                to.replace(
+                    &*new_name
+                        .with_extension("")
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy(),
+                    &fname_repr
+                )
+            );
+            if args.skip_identical && new_name.exists() && same_content(filename, &new_name)? {
+                skips.skip(
+                    "identical",
+                    filename,
+                    &format!("(same contents as {:?})", new_name),
+                );
+                reports.push(Report::new(filename, &new_name, action, "skipped"));
+                continue;
+            }
+            let ext_changed = match (new_name.extension(), filename.extension()) {
+                (Some(a), Some(b)) if args.lower_ext => !a.eq_ignore_ascii_case(b),
+                (a, b) => a != b,
+            };
+            if !args.allow_ext_change && ext_changed {
                 print!(
-                    "{}: {:?} already exists, replace <y/N>? ",
+                    "{}: extension changes from {:?} to {:?}",
                     "Warning".on_yellow().bold(),
-                    new_name
+                    filename.extension().unwrap_or_default(),
+                    new_name.extension().unwrap_or_default()
                 );
-                std::io::stdout().flush()?;
-                let mut buf = String::new();
-                std::io::stdin().read_line(&mut buf)?;
-                if !(buf.trim().to_lowercase() == "y") {
+                if args.test {
+                    println!();
+                } else {
+                    print!(", continue <y/N>? ");
+                    std::io::stdout().flush()?;
+                    let mut buf = String::new();
+                    read_input(&mut buf)?;
+                    if buf.trim().to_lowercase() != "y" {
+                        skips.skip("ext-change", filename, "(extension change declined)");
+                        reports.push(Report::new(filename, &new_name, action, "skipped"));
+                        continue;
+                    }
+                }
+            }
+            if args.test {
+                if new_name.exists() && new_name != *filename {
+                    if same_content(filename, &new_name)? {
+                        println!(
+                            "{}: {:?} already exists with identical contents",
+                            "Exists".yellow().bold(),
+                            new_name
+                        );
+                    } else {
+                        println!("{}: {:?} already exists", "Conflict".red().bold(), new_name);
+                    }
+                }
+                reports.push(Report::new(filename, &new_name, action, "planned"));
+                if args.rename || args.r#move {
+                    batch.vacated.insert(filename.clone());
+                }
+                rendered_names.push(new_name.file_name().unwrap().to_string_lossy().to_string());
+                operations.push((filename.clone(), new_name));
+                continue;
+            }
+            if new_name.exists() && on_conflict != OnConflict::Replace {
+                let replace = if on_conflict == OnConflict::Skip {
+                    false
+                } else {
+                    confirm_replace(&new_name)?
+                };
+                if !replace {
+                    skips.skip(
+                        "conflict",
+                        filename,
+                        &format!("({:?} already exists)", new_name),
+                    );
+                    reports.push(Report::new(filename, &new_name, action, "skipped"));
                     continue;
                 }
             }
+            let mut report = Report::new(filename, &new_name, action, "done");
+            let replaced = new_name != *filename && new_name.symlink_metadata().is_ok();
+            let exec_started = Instant::now();
+            let result = (|| -> Result<(), Box<dyn Error>> {
+                if let Some(dir) = group_dir.as_ref().filter(|d| !d.is_dir()) {
+                    std::fs::create_dir_all(dir)?;
+                    created_dirs.push(dir.clone());
+                }
+                apply_operation(
+                    filename,
+                    &new_name,
+                    action,
+                    args.no_follow_symlinks,
+                    args.reflink,
+                )
+            })();
+            execution += exec_started.elapsed();
+            if let Err(e) = result {
+                report.status = "failed";
+                report.error = Some(e.to_string());
+                reports.push(report);
+                if let Some(path) = &args.summary_json {
+                    write_summary(path, &args, &fmt_str, &reports)?;
+                }
+                return Err(e);
+            }
+            reports.push(report);
+            journal.record(filename, &new_name, action, replaced)?;
+            if args.rename || args.r#move {
+                batch.vacated.insert(filename.clone());
+            }
+        }
+    }
+    print_new_entries("New history entries", &old_hist, &hist);
+    skips.print();
+    if !suffixed.is_empty() {
+        println!("{}:", "Suffixed due to collisions".yellow().bold());
+        for f in suffixed {
+            println!("  {:?}", f);
+        }
+    }
+    if args.emit_command {
+        let differs: Vec<&String> = used_values
+            .iter()
+            .filter_map(|(k, v)| v.is_none().then_some(k))
+            .collect();
+        if !differs.is_empty() {
+            println!(
+                "{}: {:?} had different values for different files",
+                "Warning".on_yellow().bold(),
+                differs
+            );
         }
-        if args.rename {
-            std::fs::rename(filename, new_name)?;
+        let values = used_values
+            .into_iter()
+            .filter_map(|(k, v)| v.map(|v| (k, v)))
+            .collect();
+        // the chosen destination is only given if it was the same for all
+        let destinations: Option<Vec<PathBuf>> = if !args.ask_destination {
+            Some(args.destination.clone())
+        } else if asked_destinations.len() <= 1 {
+            Some(asked_destinations.into_iter().collect())
         } else {
-            std::fs::copy(filename, new_name)?;
-            if args.r#move {
-                std::fs::remove_file(filename)?;
-            }
+            None
+        };
+        match destinations {
+            Some(d) => println!(
+                "{}",
+                emit_command(&args, &fmt_str, templ.delimiter, &values, &d)
+            ),
+            None => println!(
+                "{}: files went to different destinations, no command to repeat the batch",
+                "Note".yellow().bold()
+            ),
+        }
+    }
+    if let Some(path) = &args.summary_json {
+        write_summary(path, &args, &fmt_str, &reports)?;
+    }
+    if args.touch_in_order && !args.test {
+        let number_of: HashMap<&PathBuf, usize> = paths.iter().zip(numbers).collect();
+        let mut done: Vec<(usize, &Path)> = reports
+            .iter()
+            .filter(|r| r.status == "done")
+            .map(|r| (number_of[&r.source], r.destination.as_path()))
+            .collect();
+        // the times follow the numbers, not the order the files were done in
+        done.sort_by_key(|(n, _)| *n);
+        touch_in_order(&done.into_iter().map(|(_, d)| d).collect::<Vec<&Path>>())?;
+    }
+    if let Some(dir_time) = args.dir_time {
+        let done: Vec<&Path> = reports
+            .iter()
+            .filter(|r| r.status == "done")
+            .map(|r| r.destination.as_path())
+            .collect();
+        set_dir_times(&created_dirs, dir_time, &done)?;
+    }
+    if let Some(path) = &args.save_plan {
+        let plan: Vec<PlannedOperation> = operations
+            .iter()
+            .map(|(src, dest)| PlannedOperation::new(src, dest, action))
+            .collect();
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(writer, &plan)?;
+        println!(
+            "{}: {} operation(s) saved to {:?}",
+            "Plan".green().bold(),
+            plan.len(),
+            path
+        );
+    }
+    if args.audit {
+        print!("{}", audit_table(&operations));
+    }
+    if let Some(kind) = args.script {
+        let copy = !(args.rename || args.r#move);
+        println!("{}", shell_script(kind, &operations, copy));
+    }
+    if args.clipboard {
+        let mut clipboard = arboard::Clipboard::new()?;
+        println!(
+            "{}: {} name(s) copied to clipboard",
+            "Clipboard".green().bold(),
+            rendered_names.len()
+        );
+        // on Linux the clipboard is served by this process, so it has to
+        // stay until another program takes over the clipboard
+        #[cfg(target_os = "linux")]
+        {
+            use arboard::SetExtLinux;
+            eprintln!("Keeping the clipboard until something else is copied");
+            clipboard.set().wait().text(rendered_names.join("\n"))?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        clipboard.set_text(rendered_names.join("\n"))?;
+    }
+    if args.timings {
+        let total = started.elapsed();
+        let prompting = Duration::from_nanos(PROMPT_NANOS.load(Ordering::Relaxed));
+        eprintln!("{}:", "Timings".blue().bold());
+        eprintln!(
+            "  planning: {:.3?}",
+            total.saturating_sub(prompting + execution)
+        );
+        eprintln!("  prompting: {:.3?}", prompting);
+        eprintln!("  execution: {:.3?}", execution);
+        eprintln!("  total: {:.3?}", total);
+        if !paths.is_empty() {
+            eprintln!(
+                "  per file: {:.3?}",
+                loop_started.elapsed() / paths.len() as u32
+            );
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn var_settings() {
+        assert_eq!(
+            parse_var_setting("title=a=b").unwrap(),
+            ("title", Some("a=b"))
+        );
+        assert_eq!(parse_var_setting("title==").unwrap(), ("title", Some("=")));
+        assert_eq!(
+            parse_var_setting("title=two words").unwrap(),
+            ("title", Some("two words"))
+        );
+        assert_eq!(
+            parse_var_setting(" title = a ").unwrap(),
+            ("title", Some("a"))
+        );
+        assert_eq!(
+            parse_var_setting("title=' a = b '").unwrap(),
+            ("title", Some(" a = b "))
+        );
+        assert_eq!(parse_var_setting("title=\"\"").unwrap(), ("title", None));
+        assert_eq!(parse_var_setting("title=").unwrap(), ("title", None));
+        assert!(parse_var_setting("title").is_err());
+    }
+
+    /// Directory for the files of a test, removed when dropped so a
+    /// failing test doesn't leave it behind
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("nameit-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl std::ops::Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    #[test]
+    fn separate_batches() {
+        let dir = TempDir::new("journal");
+        let file = dir.join("a.txt");
+        File::create(&file).unwrap();
+        let path = dir.join("journal.jsonl");
+        // two runs started right after each other
+        let (first, second) = (Journal::new(path.clone()), Journal::new(path.clone()));
+        first.record(&file, &file, Action::Copy, false).unwrap();
+        second.record(&file, &file, Action::Copy, false).unwrap();
+        let entries = second.entries().unwrap();
+        assert_ne!(first.batch, second.batch);
+        let batches: Vec<&str> = entries.iter().map(|e| e.batch.as_str()).collect();
+        assert_eq!(batches, [first.batch.as_str(), second.batch.as_str()]);
+    }
+
+    #[test]
+    fn copy_after_fill() {
+        let templ = NameTemplate::parse("x_@daily", '_').unwrap();
+        let planner = Planner::new(templ.clone(), HashMap::new());
+        let mut batch = Batch::default();
+        let mut names = Vec::new();
+        for copy in [None, Some(1)] {
+            let mut parts = vec!["x".to_string(), "_".to_string(), String::new()];
+            batch
+                .fill(&templ, &mut parts, &HashMap::new(), Path::new(""))
+                .unwrap();
+            let fname = planner.file_name(Path::new("a.jpg"), &templ, &parts);
+            names.push(copy_name(fname.unwrap(), copy));
+        }
+        assert_eq!(names, ["x_1", "x_2-copy1"]);
+    }
+
+    #[test]
+    fn script_dirs() {
+        let operations = [
+            (
+                PathBuf::from("a.txt"),
+                PathBuf::from("nameit-missing/x/a.txt"),
+            ),
+            (
+                PathBuf::from("b.txt"),
+                PathBuf::from("nameit-missing/x/b.txt"),
+            ),
+            (PathBuf::from("c.txt"), PathBuf::from("c-1.txt")),
+        ];
+        let script = shell_script(ScriptKind::Sh, &operations, true);
+        let lines: Vec<&str> = script.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "#!/bin/sh",
+                "mkdir -p -- 'nameit-missing/x'",
+                "cp -- 'a.txt' 'nameit-missing/x/a.txt'",
+                "cp -- 'b.txt' 'nameit-missing/x/b.txt'",
+                "cp -- 'c.txt' 'c-1.txt'",
+            ]
+        );
+        let script = shell_script(ScriptKind::Ps1, &operations[..1], false);
+        assert_eq!(
+            script.lines().next(),
+            Some("New-Item -ItemType Directory -Force -Path 'nameit-missing/x' | Out-Null")
+        );
+    }
+
+    #[test]
+    fn recursive_sidecars() {
+        let dir = TempDir::new("sidecars");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let files = [
+            "a.jpg",
+            "a.jpg.nameit",
+            "a.meta",
+            "notes.meta",
+            "sub/b.txt",
+            "sub/orphan.nameit",
+        ];
+        for f in files {
+            std::fs::write(dir.join(f), "").unwrap();
+        }
+        let mut skips = Skips::default();
+        let found: Vec<PathBuf> = dir_files(&dir, "meta", &mut skips)
+            .unwrap()
+            .iter()
+            .map(|f| f.strip_prefix(&*dir).unwrap().to_path_buf())
+            .collect();
+        assert_eq!(
+            found,
+            ["a.jpg", "notes.meta", "sub/b.txt", "sub/orphan.nameit"].map(PathBuf::from)
+        );
+        assert_eq!(skips.0.get("sidecar"), Some(&2));
+    }
+
+    #[test]
+    fn plan_chained_renames() {
+        let dir = TempDir::new("plan");
+        let [a, b, c] = ["a", "b", "c"].map(|f| dir.join(f));
+        std::fs::write(&a, "a").unwrap();
+        std::fs::write(&b, "b").unwrap();
+        let plan = [
+            PlannedOperation::new(&b, &c, Action::Rename),
+            PlannedOperation::new(&a, &b, Action::Rename),
+        ];
+        let mut asked = Vec::new();
+        let checked = check_plan(&plan, |d| {
+            asked.push(d.to_path_buf());
+            Ok(false)
+        });
+        assert_eq!(checked.unwrap().len(), 2);
+        assert!(asked.is_empty());
+        // `a` can't go to `b` before `b` is moved away
+        let reversed = [plan[1].clone(), plan[0].clone()];
+        assert_eq!(check_plan(&reversed, |_| Ok(false)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn undo_chained_renames() {
+        let dir = TempDir::new("undo");
+        let [a, b, c] = ["a", "b", "c"].map(|f| dir.join(f));
+        std::fs::write(&a, "a").unwrap();
+        std::fs::write(&b, "b").unwrap();
+        let journal = Journal::new(dir.join("journal.jsonl"));
+        // `b` is moved away before `a` takes its name
+        for (src, dest) in [(&b, &c), (&a, &b)] {
+            apply_operation(src, dest, Action::Rename, false, Reflink::Never).unwrap();
+            journal.record(src, dest, Action::Rename, false).unwrap();
+        }
+        assert!(undo_batch(&journal, None, false, Reflink::Never).is_ok());
+        let contents = [&a, &b].map(|f| std::fs::read_to_string(f).ok());
+        assert_eq!(contents, [Some("a".to_string()), Some("b".to_string())]);
+        assert!(!c.exists());
+    }
+}